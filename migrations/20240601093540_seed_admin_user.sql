@@ -0,0 +1,11 @@
+-- Add migration script here
+-- Seed the first admin account. The password is `everythinghastostartsomewhere`
+-- and must be rotated straight after the first deployment.
+INSERT INTO
+    users (user_id, username, password_hash)
+VALUES
+    (
+        'ddf8994f-d522-4659-8d02-c1d479057be6',
+        'admin',
+        '$argon2id$v=19$m=15000,t=2,p=1$euAGZ2Wa2/2JsbvDcTRu4g$v/sttyDjRL8dIDhL89s1dzIKUF6oBngyMiFShYb2JV4'
+    );
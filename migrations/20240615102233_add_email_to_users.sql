@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Optional for now, since the seeded admin user does not have one yet.
+ALTER TABLE users
+ADD COLUMN email TEXT NULL UNIQUE;
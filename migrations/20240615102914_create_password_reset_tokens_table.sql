@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE
+    password_reset_tokens (
+        password_reset_token TEXT NOT NULL,
+        user_id uuid NOT NULL REFERENCES users (user_id),
+        expires_at timestamptz NOT NULL,
+        PRIMARY KEY (password_reset_token)
+    );
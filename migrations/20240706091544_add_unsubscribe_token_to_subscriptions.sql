@@ -0,0 +1,19 @@
+-- Every subscriber gets a long-lived token to leave the list.
+-- Added in a transaction so it fails or succeeds as a whole
+BEGIN;
+
+ALTER TABLE subscriptions
+ADD COLUMN unsubscribe_token TEXT NULL UNIQUE;
+
+-- Backfill existing subscribers with a random token
+UPDATE subscriptions
+SET
+    unsubscribe_token = replace(gen_random_uuid()::text, '-', '')
+WHERE
+    unsubscribe_token IS NULL;
+
+ALTER TABLE subscriptions
+ALTER COLUMN unsubscribe_token
+SET NOT NULL;
+
+COMMIT;
@@ -0,0 +1,21 @@
+-- Confirmation links expire, so we need to know when each token was issued.
+-- Added in a transaction so it fails or succeeds as a whole
+BEGIN;
+
+ALTER TABLE subscription_tokens
+ADD COLUMN created_at timestamptz NULL;
+
+-- Existing tokens were issued when their subscriber signed up
+UPDATE subscription_tokens
+SET
+    created_at = subscriptions.subscribed_at
+FROM
+    subscriptions
+WHERE
+    subscriptions.id = subscription_tokens.subscriber_id;
+
+ALTER TABLE subscription_tokens
+ALTER COLUMN created_at
+SET NOT NULL;
+
+COMMIT;
@@ -0,0 +1,4 @@
+-- The id of the request that published the issue, so that its delivery
+-- can be traced back to it
+ALTER TABLE issue_delivery_queue
+ADD COLUMN request_id TEXT NULL;
@@ -0,0 +1,16 @@
+-- Deliveries that fail are tried again later, with an increasing delay
+ALTER TABLE issue_delivery_queue
+ADD COLUMN n_retries SMALLINT NOT NULL DEFAULT 0,
+ADD COLUMN execute_after TIMESTAMPTZ NOT NULL DEFAULT now();
+
+-- Deliveries given up on after too many attempts, kept for inspection
+CREATE TABLE
+    failed_deliveries (
+        newsletter_issue_id uuid NOT NULL REFERENCES newsletter_issues (newsletter_issue_id),
+        subscriber_email TEXT NOT NULL,
+        n_retries SMALLINT NOT NULL,
+        request_id TEXT NULL,
+        last_error TEXT NOT NULL,
+        failed_at TIMESTAMPTZ NOT NULL,
+        PRIMARY KEY (newsletter_issue_id, subscriber_email)
+    );
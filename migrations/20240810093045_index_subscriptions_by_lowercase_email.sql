@@ -0,0 +1,2 @@
+-- Subscribers are looked up by email regardless of its case.
+CREATE INDEX subscriptions_lower_email_idx ON subscriptions (lower(email));
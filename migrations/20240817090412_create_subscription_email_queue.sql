@@ -0,0 +1,12 @@
+-- Emails sent in reply to a signup, queued by the request and sent by a
+-- background worker so that every signup takes about the same time.
+CREATE TABLE subscription_email_queue (
+    id UUID NOT NULL PRIMARY KEY,
+    subscriber_id UUID NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+    kind TEXT NOT NULL CHECK (kind IN ('confirmation', 'already_subscribed')),
+    -- A revoked link is not worth sending.
+    subscription_token TEXT NULL REFERENCES subscription_tokens (subscription_token) ON DELETE CASCADE,
+    request_id TEXT NULL,
+    n_retries SMALLINT NOT NULL DEFAULT 0,
+    execute_after TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,9 @@
+-- Two addresses differing only in case are the same subscriber.
+-- Fails if the table already holds such duplicates: merge them first.
+BEGIN;
+
+DROP INDEX subscriptions_lower_email_idx;
+CREATE UNIQUE INDEX subscriptions_lower_email_key ON subscriptions (lower(email));
+ALTER TABLE subscriptions DROP CONSTRAINT subscriptions_email_key;
+
+COMMIT;
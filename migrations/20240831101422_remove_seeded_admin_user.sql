@@ -0,0 +1,14 @@
+-- The seeded admin's password was public. Remove the account unless its
+-- password has been changed: the first admin now comes from the `admin`
+-- settings instead.
+WITH seeded_admin AS (
+    SELECT user_id FROM users
+    WHERE
+        user_id = 'ddf8994f-d522-4659-8d02-c1d479057be6' AND
+        password_hash = '$argon2id$v=19$m=15000,t=2,p=1$euAGZ2Wa2/2JsbvDcTRu4g$v/sttyDjRL8dIDhL89s1dzIKUF6oBngyMiFShYb2JV4'
+), idempotency_keys AS (
+    DELETE FROM idempotency WHERE user_id IN (SELECT user_id FROM seeded_admin)
+), reset_tokens AS (
+    DELETE FROM password_reset_tokens WHERE user_id IN (SELECT user_id FROM seeded_admin)
+)
+DELETE FROM users WHERE user_id IN (SELECT user_id FROM seeded_admin);
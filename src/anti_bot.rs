@@ -0,0 +1,169 @@
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use std::time::Duration;
+
+/// When a form was rendered, signed so that clients cannot make it up.
+///
+/// Humans need a few seconds to fill in a form; bots that post it straight
+/// away, or post it without ever rendering it, give themselves away. Bots
+/// that replay a timestamp scraped once are stopped by its maximum age.
+pub struct SignedTimestamp {
+    pub timestamp: i64,
+    pub signature: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BotCheckFailure {
+    HoneypotFilled,
+    MissingTimestamp,
+    InvalidSignature,
+    SubmittedTooFast,
+    FormExpired,
+}
+
+impl std::fmt::Display for BotCheckFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BotCheckFailure::HoneypotFilled => write!(f, "The honeypot field was filled in."),
+            BotCheckFailure::MissingTimestamp => write!(f, "The form timestamp was missing."),
+            BotCheckFailure::InvalidSignature => {
+                write!(f, "The form timestamp signature is invalid.")
+            }
+            BotCheckFailure::SubmittedTooFast => {
+                write!(f, "The form was submitted too soon after being rendered.")
+            }
+            BotCheckFailure::FormExpired => {
+                write!(f, "The form was submitted too long after being rendered.")
+            }
+        }
+    }
+}
+
+/// Signs and checks the render timestamps of public forms.
+pub struct FormTimestampSigner {
+    signing_key: Secret<String>,
+    minimum_fill_time: Duration,
+    maximum_age: Duration,
+}
+
+impl FormTimestampSigner {
+    pub fn new(
+        signing_key: Secret<String>,
+        minimum_fill_time: Duration,
+        maximum_age: Duration,
+    ) -> Self {
+        Self {
+            signing_key,
+            minimum_fill_time,
+            maximum_age,
+        }
+    }
+
+    /// Sign `timestamp`, in seconds since the Unix epoch.
+    pub fn sign(&self, timestamp: i64) -> SignedTimestamp {
+        let signature = hex::encode(self.mac(timestamp).finalize().into_bytes());
+        SignedTimestamp {
+            timestamp,
+            signature,
+        }
+    }
+
+    /// Check that the form was rendered by us, at least `minimum_fill_time`
+    /// and at most `maximum_age` before `now`.
+    pub fn verify(&self, timestamp: i64, signature: &str, now: i64) -> Result<(), BotCheckFailure> {
+        let signature = hex::decode(signature).map_err(|_| BotCheckFailure::InvalidSignature)?;
+        self.mac(timestamp)
+            .verify_slice(&signature)
+            .map_err(|_| BotCheckFailure::InvalidSignature)?;
+
+        let age = now.saturating_sub(timestamp);
+        let minimum_fill_time = i64::try_from(self.minimum_fill_time.as_secs()).unwrap_or(i64::MAX);
+        if age < minimum_fill_time {
+            return Err(BotCheckFailure::SubmittedTooFast);
+        }
+        let maximum_age = i64::try_from(self.maximum_age.as_secs()).unwrap_or(i64::MAX);
+        if age > maximum_age {
+            return Err(BotCheckFailure::FormExpired);
+        }
+        Ok(())
+    }
+
+    fn mac(&self, timestamp: i64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(self.signing_key.expose_secret().as_bytes()).unwrap();
+        mac.update(format!("form_timestamp={}", timestamp).as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BotCheckFailure, FormTimestampSigner};
+    use claims::{assert_err_eq, assert_ok};
+    use secrecy::Secret;
+    use std::time::Duration;
+
+    fn signer() -> FormTimestampSigner {
+        signer_with_key("secret")
+    }
+
+    fn signer_with_key(key: &str) -> FormTimestampSigner {
+        FormTimestampSigner::new(
+            Secret::new(key.into()),
+            Duration::from_secs(3),
+            Duration::from_secs(3_600),
+        )
+    }
+
+    #[test]
+    fn a_form_submitted_after_the_minimum_fill_time_passes() {
+        let signed = signer().sign(1_000);
+        assert_ok!(signer().verify(signed.timestamp, &signed.signature, 1_003));
+    }
+
+    #[test]
+    fn a_form_submitted_too_fast_is_rejected() {
+        let signed = signer().sign(1_000);
+        assert_err_eq!(
+            signer().verify(signed.timestamp, &signed.signature, 1_002),
+            BotCheckFailure::SubmittedTooFast
+        );
+    }
+
+    #[test]
+    fn a_form_submitted_after_the_maximum_age_is_rejected() {
+        let signed = signer().sign(1_000);
+        assert_ok!(signer().verify(signed.timestamp, &signed.signature, 4_600));
+        assert_err_eq!(
+            signer().verify(signed.timestamp, &signed.signature, 4_601),
+            BotCheckFailure::FormExpired
+        );
+    }
+
+    #[test]
+    fn a_tampered_timestamp_is_rejected() {
+        let signed = signer().sign(1_000);
+        assert_err_eq!(
+            signer().verify(900, &signed.signature, 1_003),
+            BotCheckFailure::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn a_timestamp_signed_with_another_key_is_rejected() {
+        let signed = signer_with_key("other").sign(1_000);
+        assert_err_eq!(
+            signer().verify(signed.timestamp, &signed.signature, 1_003),
+            BotCheckFailure::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn a_malformed_signature_is_rejected() {
+        assert_err_eq!(
+            signer().verify(1_000, "not-hex", 1_003),
+            BotCheckFailure::InvalidSignature
+        );
+    }
+}
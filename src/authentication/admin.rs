@@ -0,0 +1,31 @@
+use crate::configuration::AdminSettings;
+use secrecy::ExposeSecret;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Create the admin account described in the configuration, unless a user
+/// with that username already exists, and set their email if one is given.
+#[tracing::instrument(name = "Create admin", skip_all, fields(username = %admin.username))]
+pub async fn create_admin(
+    admin: &AdminSettings,
+    connection_pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let created = sqlx::query!(
+        r#"INSERT INTO users (user_id, username, password_hash, email)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (username) DO UPDATE
+        SET email = COALESCE(EXCLUDED.email, users.email)
+        RETURNING xmax = 0 AS "created!""#,
+        Uuid::new_v4(),
+        admin.username,
+        admin.password_hash.expose_secret(),
+        admin.email,
+    )
+    .fetch_one(connection_pool)
+    .await?
+    .created;
+    if created {
+        tracing::info!("Created the admin account");
+    }
+    Ok(())
+}
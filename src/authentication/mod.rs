@@ -0,0 +1,7 @@
+mod admin;
+mod middleware;
+mod password;
+
+pub use admin::create_admin;
+pub use middleware::{reject_anonymous_users, UserId};
+pub use password::{change_password, validate_credentials, AuthError};
@@ -0,0 +1,166 @@
+use crate::domain::{Credentials, NewPassword};
+use crate::session_store::delete_user_sessions;
+use crate::telemetry::spawn_blocking_with_tracing;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use tokio::task::JoinError;
+use uuid::Uuid;
+
+// A valid PHC string that no user can log in with. We verify against it
+// when the username is unknown, so that failed lookups cost as much as a
+// real password check and response times do not reveal which usernames exist.
+const FALLBACK_PASSWORD_HASH: &str = "$argon2id$v=19$m=15000,t=2,p=1$\
+    f0HozGSKgsc2fa9o27bcaw$BDxcW+B4BpHwpbutCN9MqspnJH/Nunjq1V3t0NAPlQg";
+
+#[derive(Debug)]
+pub enum AuthError {
+    InvalidCredentials,
+    Database(sqlx::Error),
+    PasswordHash(argon2::password_hash::Error),
+    TaskJoin(JoinError),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::InvalidCredentials => write!(f, "Invalid credentials."),
+            AuthError::Database(_) => write!(f, "Failed to retrieve stored credentials."),
+            AuthError::PasswordHash(_) => write!(f, "Failed to process the password hash."),
+            AuthError::TaskJoin(_) => write!(f, "Failed to run the password hashing task."),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AuthError::InvalidCredentials => None,
+            AuthError::Database(e) => Some(e),
+            AuthError::PasswordHash(e) => Some(e),
+            AuthError::TaskJoin(e) => Some(e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for AuthError {
+    fn from(error: sqlx::Error) -> Self {
+        AuthError::Database(error)
+    }
+}
+
+impl From<JoinError> for AuthError {
+    fn from(error: JoinError) -> Self {
+        AuthError::TaskJoin(error)
+    }
+}
+
+#[tracing::instrument(name = "Validate credentials", skip(credentials, connection_pool))]
+pub async fn validate_credentials(
+    credentials: Credentials,
+    connection_pool: &PgPool,
+) -> Result<Uuid, AuthError> {
+    let mut user_id = None;
+    let mut expected_password_hash = Secret::new(FALLBACK_PASSWORD_HASH.to_string());
+
+    if let Some((stored_user_id, stored_password_hash)) =
+        get_stored_credentials(&credentials.username, connection_pool).await?
+    {
+        user_id = Some(stored_user_id);
+        expected_password_hash = stored_password_hash;
+    }
+
+    spawn_blocking_with_tracing(move || {
+        verify_password_hash(expected_password_hash, credentials.password)
+    })
+    .await??;
+
+    // Only reachable if the password matched a stored hash, which the
+    // fallback hash never does.
+    user_id.ok_or(AuthError::InvalidCredentials)
+}
+
+/// Set a new password for `user_id` and log them out of every session.
+#[tracing::instrument(name = "Change password", skip(password, connection_pool))]
+pub async fn change_password(
+    user_id: Uuid,
+    password: NewPassword,
+    connection_pool: &PgPool,
+) -> Result<(), AuthError> {
+    let password_hash =
+        spawn_blocking_with_tracing(move || compute_password_hash(password.inner())).await??;
+
+    let mut transaction = connection_pool.begin().await?;
+    sqlx::query!(
+        r#"UPDATE users SET password_hash = $1 WHERE user_id = $2"#,
+        password_hash.expose_secret(),
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    // Whoever knew the old password must not stay logged in.
+    delete_user_sessions(&mut transaction, user_id).await?;
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+fn compute_password_hash(password: Secret<String>) -> Result<Secret<String>, AuthError> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let password_hash = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(15000, 2, 1, None).map_err(|e| AuthError::PasswordHash(e.into()))?,
+    )
+    .hash_password(password.expose_secret().as_bytes(), &salt)
+    .map_err(AuthError::PasswordHash)?
+    .to_string();
+
+    Ok(Secret::new(password_hash))
+}
+
+#[tracing::instrument(
+    name = "Verify password hash",
+    skip(expected_password_hash, password_candidate)
+)]
+fn verify_password_hash(
+    expected_password_hash: Secret<String>,
+    password_candidate: Secret<String>,
+) -> Result<(), AuthError> {
+    let expected_password_hash = PasswordHash::new(expected_password_hash.expose_secret())
+        .map_err(AuthError::PasswordHash)?;
+
+    Argon2::default()
+        .verify_password(
+            password_candidate.expose_secret().as_bytes(),
+            &expected_password_hash,
+        )
+        .map_err(|e| match e {
+            argon2::password_hash::Error::Password => AuthError::InvalidCredentials,
+            other => AuthError::PasswordHash(other),
+        })
+}
+
+#[tracing::instrument(name = "Get stored credentials", skip(username, connection_pool))]
+async fn get_stored_credentials(
+    username: &str,
+    connection_pool: &PgPool,
+) -> Result<Option<(Uuid, Secret<String>)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT user_id, password_hash FROM users WHERE username = $1"#,
+        username,
+    )
+    .fetch_optional(connection_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(row.map(|r| (r.user_id, Secret::new(r.password_hash))))
+}
@@ -0,0 +1,11 @@
+use secrecy::Secret;
+
+/// A username/password pair as submitted by someone trying to authenticate.
+///
+/// The password is kept behind a `Secret` so that it never shows up
+/// in logs or `Debug` output by accident.
+#[derive(Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
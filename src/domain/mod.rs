@@ -1,7 +1,11 @@
+mod credentials;
+mod new_password;
 mod new_subscriber;
 mod subscriber_email;
 mod subscriber_name;
 
+pub use credentials::Credentials;
+pub use new_password::NewPassword;
 pub use new_subscriber::NewSubscriber;
-pub use subscriber_email::SubscriberEmail;
-pub use subscriber_name::SubscriberName;
+pub use subscriber_email::{SubscriberEmail, SubscriberEmailError};
+pub use subscriber_name::{SubscriberName, SubscriberNameError};
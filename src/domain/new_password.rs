@@ -0,0 +1,71 @@
+use secrecy::{ExposeSecret, Secret};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// A password that satisfies our length rules, ready to be hashed.
+///
+/// We follow OWASP's guidance: long enough to resist guessing, but capped
+/// so that hashing attacker-provided input stays cheap.
+#[derive(Debug)]
+pub struct NewPassword(Secret<String>);
+
+impl NewPassword {
+    const MIN_LENGTH: usize = 12;
+    const MAX_LENGTH: usize = 128;
+
+    pub fn parse(s: Secret<String>) -> Result<Self, String> {
+        let length = s.expose_secret().graphemes(true).count();
+        if length < Self::MIN_LENGTH {
+            return Err(format!(
+                "The new password must be at least {} characters long.",
+                Self::MIN_LENGTH
+            ));
+        }
+        if length > Self::MAX_LENGTH {
+            return Err(format!(
+                "The new password must be at most {} characters long.",
+                Self::MAX_LENGTH
+            ));
+        }
+        Ok(Self(s))
+    }
+
+    pub fn inner(self) -> Secret<String> {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NewPassword;
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+
+    #[test]
+    fn a_12_grapheme_long_password_is_valid() {
+        let password = "ë".repeat(12);
+        assert_ok!(NewPassword::parse(Secret::new(password)));
+    }
+
+    #[test]
+    fn a_password_shorter_than_12_graphemes_is_rejected() {
+        let password = "ë".repeat(11);
+        assert_err!(NewPassword::parse(Secret::new(password)));
+    }
+
+    #[test]
+    fn a_128_grapheme_long_password_is_valid() {
+        let password = "ë".repeat(128);
+        assert_ok!(NewPassword::parse(Secret::new(password)));
+    }
+
+    #[test]
+    fn a_password_longer_than_128_graphemes_is_rejected() {
+        let password = "ë".repeat(129);
+        assert_err!(NewPassword::parse(Secret::new(password)));
+    }
+
+    #[test]
+    fn empty_string_is_rejected() {
+        assert_err!(NewPassword::parse(Secret::new("".to_string())));
+    }
+}
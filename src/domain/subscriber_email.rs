@@ -5,14 +5,50 @@ use validator::ValidateEmail;
 pub struct SubscriberEmail(String);
 
 impl SubscriberEmail {
-    pub fn parse(s: String) -> Result<Self, String> {
-        if ValidateEmail::validate_email(&s) {
-            return Ok(Self(s));
+    pub fn parse(s: String) -> Result<Self, SubscriberEmailError> {
+        if s.trim().is_empty() {
+            return Err(SubscriberEmailError::Empty);
         }
-        Err(format!("{} is not a valid email address", s))
+        let Some((username, domain)) = s.rsplit_once('@') else {
+            return Err(SubscriberEmailError::MissingAtSymbol);
+        };
+        if username.is_empty() {
+            return Err(SubscriberEmailError::MissingUsername);
+        }
+        if domain.is_empty() {
+            return Err(SubscriberEmailError::MissingDomain);
+        }
+        if !ValidateEmail::validate_email(&s) {
+            return Err(SubscriberEmailError::Invalid);
+        }
+        Ok(Self(s))
     }
 }
 
+/// Why a string was rejected as a `SubscriberEmail`.
+#[derive(Debug, PartialEq)]
+pub enum SubscriberEmailError {
+    Empty,
+    MissingAtSymbol,
+    MissingUsername,
+    MissingDomain,
+    Invalid,
+}
+
+impl std::fmt::Display for SubscriberEmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubscriberEmailError::Empty => write!(f, "email must not be empty"),
+            SubscriberEmailError::MissingAtSymbol => write!(f, "email missing '@' symbol"),
+            SubscriberEmailError::MissingUsername => write!(f, "email missing username"),
+            SubscriberEmailError::MissingDomain => write!(f, "email missing domain"),
+            SubscriberEmailError::Invalid => write!(f, "email is not a valid address"),
+        }
+    }
+}
+
+impl std::error::Error for SubscriberEmailError {}
+
 impl AsRef<str> for SubscriberEmail {
     fn as_ref(&self) -> &str {
         &self.0
@@ -20,10 +56,9 @@ impl AsRef<str> for SubscriberEmail {
 }
 
 #[cfg(test)]
-
 mod tests {
-    use super::SubscriberEmail;
-    use claims::assert_err;
+    use super::{SubscriberEmail, SubscriberEmailError};
+    use claims::{assert_err, assert_err_eq};
     use fake::faker::internet::en::SafeEmail;
     use fake::Fake;
 
@@ -51,6 +86,24 @@ mod tests {
         assert_err!(SubscriberEmail::parse(email));
     }
 
+    #[test]
+    fn the_error_says_which_part_of_the_email_is_missing() {
+        let cases = [
+            ("", SubscriberEmailError::Empty),
+            ("abhishek_roy.com", SubscriberEmailError::MissingAtSymbol),
+            ("@domain.com", SubscriberEmailError::MissingUsername),
+            ("abhishek_roy@", SubscriberEmailError::MissingDomain),
+            ("abhishek_roy@.com", SubscriberEmailError::Invalid),
+        ];
+        for (email, expected) in cases {
+            assert_err_eq!(SubscriberEmail::parse(email.to_string()), expected);
+        }
+        assert_eq!(
+            SubscriberEmailError::MissingDomain.to_string(),
+            "email missing domain"
+        );
+    }
+
     #[derive(Debug, Clone)]
     struct ValidEmailFixture(pub String);
 
@@ -3,31 +3,48 @@ use unicode_segmentation::UnicodeSegmentation;
 #[derive(Debug)]
 pub struct SubscriberName(String);
 
-impl SubscriberName {
-    pub fn parse(s: String) -> Result<Self, String> {
-        // validate the input
-
-        // 1. check not empty
-
-        let is_empty = s.trim().is_empty();
-
-        // 2. check length
+/// Why a string was rejected as a `SubscriberName`.
+#[derive(Debug, PartialEq)]
+pub enum SubscriberNameError {
+    Empty,
+    TooLong,
+    ForbiddenCharacter(char),
+}
 
-        let is_too_long = s.graphemes(true).count() > 256;
+impl std::fmt::Display for SubscriberNameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubscriberNameError::Empty => write!(f, "name must not be empty"),
+            SubscriberNameError::TooLong => {
+                write!(f, "name must not be longer than {} characters", MAX_LENGTH)
+            }
+            SubscriberNameError::ForbiddenCharacter(c) => {
+                write!(f, "name contains forbidden character '{}'", c)
+            }
+        }
+    }
+}
 
-        // 3. check for invalid chars
+impl std::error::Error for SubscriberNameError {}
 
-        let invalid_chars = [
-            '/', '{', '}', '[', ']', '(', ')', '.', ',', '|', '\\', '"', '<', '>',
-        ];
+const MAX_LENGTH: usize = 256;
 
-        let has_invalid_char = s.chars().any(|grapheme| invalid_chars.contains(&grapheme));
+const FORBIDDEN_CHARACTERS: [char; 14] = [
+    '/', '{', '}', '[', ']', '(', ')', '.', ',', '|', '\\', '"', '<', '>',
+];
 
-        if is_empty || is_too_long || has_invalid_char {
-            Err(format!("{} is not a valid subcriber name.", s))
-        } else {
-            Ok(Self(s))
+impl SubscriberName {
+    pub fn parse(s: String) -> Result<Self, SubscriberNameError> {
+        if s.trim().is_empty() {
+            return Err(SubscriberNameError::Empty);
         }
+        if s.graphemes(true).count() > MAX_LENGTH {
+            return Err(SubscriberNameError::TooLong);
+        }
+        if let Some(c) = s.chars().find(|c| FORBIDDEN_CHARACTERS.contains(c)) {
+            return Err(SubscriberNameError::ForbiddenCharacter(c));
+        }
+        Ok(Self(s))
     }
     pub fn inner(self) -> String {
         self.0
@@ -42,8 +59,8 @@ impl AsRef<str> for SubscriberName {
 
 #[cfg(test)]
 mod tests {
-    use crate::domain::SubscriberName;
-    use claims::{assert_err, assert_ok};
+    use crate::domain::{SubscriberName, SubscriberNameError};
+    use claims::{assert_err, assert_err_eq, assert_ok};
 
     #[test]
     fn a_256_grapheme_long_name_is_valid() {
@@ -82,4 +99,20 @@ mod tests {
         let name = "Abhishek Roy";
         assert_ok!(SubscriberName::parse(name.to_string()));
     }
+
+    #[test]
+    fn the_error_names_the_first_forbidden_character() {
+        let name = "Ursula <Le> Guin".to_string();
+        let error = assert_err!(SubscriberName::parse(name));
+        assert_eq!(error, SubscriberNameError::ForbiddenCharacter('<'));
+        assert_eq!(error.to_string(), "name contains forbidden character '<'");
+    }
+
+    #[test]
+    fn whitespace_only_names_are_reported_as_empty() {
+        assert_err_eq!(
+            SubscriberName::parse("   ".to_string()),
+            SubscriberNameError::Empty
+        );
+    }
 }
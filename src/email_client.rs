@@ -1,198 +0,0 @@
-use crate::domain::SubscriberEmail;
-use reqwest::{Client, Url};
-use secrecy::{ExposeSecret, Secret};
-use serde::Serialize;
-use std::time::Duration;
-use url::ParseError;
-pub struct EmailClient {
-    sender: SubscriberEmail,
-    http_client: Client,
-    base_url: Url,
-    authorization_token: Secret<String>,
-}
-
-#[derive(Debug)]
-pub enum EmailClientError {
-    UrlParseError(ParseError),
-    Reqwest(reqwest::Error),
-}
-
-impl From<reqwest::Error> for EmailClientError {
-    fn from(error: reqwest::Error) -> Self {
-        EmailClientError::Reqwest(error)
-    }
-}
-
-impl From<ParseError> for EmailClientError {
-    fn from(error: ParseError) -> Self {
-        EmailClientError::UrlParseError(error)
-    }
-}
-
-fn parse_url(s: String) -> Result<Url, String> {
-    Url::parse(&s).map_err(|_| "".to_string())
-}
-
-impl EmailClient {
-    pub fn new(
-        base_url: String,
-        sender: SubscriberEmail,
-        authorization_token: Secret<String>,
-        timeout: Duration,
-    ) -> Self {
-        let base_url = parse_url(base_url).expect("Invalid base URL");
-        Self {
-            http_client: Client::builder().timeout(timeout).build().unwrap(),
-            base_url,
-            sender,
-            authorization_token,
-        }
-    }
-
-    pub async fn send_email(
-        &self,
-        recepient: &SubscriberEmail,
-        subject: &str,
-        html_body: &str,
-        text_body: &str,
-    ) -> Result<(), EmailClientError> {
-        let url = self.base_url.join("/email")?;
-
-        let request_body = SendEmailRequest {
-            from: self.sender.as_ref(),
-            to: recepient.as_ref(),
-            subject,
-            html_body,
-            text_body,
-        };
-
-        self.http_client
-            .post(url)
-            .json(&request_body)
-            .header(
-                "X-Postmark-Server-Token",
-                self.authorization_token.expose_secret(),
-            )
-            .send()
-            .await?
-            .error_for_status()?;
-        Ok(())
-    }
-}
-
-#[derive(Serialize)]
-#[serde(rename_all = "PascalCase")]
-struct SendEmailRequest<'a> {
-    from: &'a str,
-    to: &'a str,
-    subject: &'a str,
-    html_body: &'a str,
-    text_body: &'a str,
-}
-
-#[cfg(test)]
-mod tests {
-    use super::EmailClient;
-    use crate::domain::SubscriberEmail;
-    use claims::{assert_err, assert_ok};
-    use fake::faker::internet::en::SafeEmail;
-    use fake::faker::lorem::en::{Paragraph, Sentence};
-    use fake::{Fake, Faker};
-    use secrecy::Secret;
-    use std::time::Duration;
-    use wiremock::matchers::{any, header, header_exists, method, path};
-    use wiremock::{Mock, MockServer, ResponseTemplate};
-
-    struct SendEmailBodyMatcher;
-
-    impl wiremock::Match for SendEmailBodyMatcher {
-        fn matches(&self, request: &wiremock::Request) -> bool {
-            let body: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
-            if let Ok(body) = body {
-                body.get("From").is_some()
-                    && body.get("To").is_some()
-                    && body.get("Subject").is_some()
-                    && body.get("TextBody").is_some()
-            } else {
-                false
-            }
-        }
-    }
-
-    fn email() -> SubscriberEmail {
-        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
-    }
-
-    fn subject() -> String {
-        Sentence(1..2).fake()
-    }
-
-    fn body() -> String {
-        Paragraph(1..10).fake()
-    }
-
-    fn email_client(base_url: String) -> EmailClient {
-        EmailClient::new(
-            base_url,
-            email(),
-            Secret::new(Faker.fake()),
-            Duration::from_millis(200),
-        )
-    }
-
-    #[tokio::test]
-    async fn send_email_fires_a_request_to_base_url() {
-        let mock_server = MockServer::start().await;
-        let email_client = email_client(mock_server.uri());
-
-        Mock::given(header_exists("X-Postmark-Server-Token"))
-            .and(header("Content-Type", "application/json"))
-            .and(path("/email"))
-            .and(method("POST"))
-            .and(SendEmailBodyMatcher)
-            .respond_with(ResponseTemplate::new(200))
-            .expect(1)
-            .mount(&mock_server)
-            .await;
-
-        let response = email_client
-            .send_email(&email(), &subject(), &body(), &body())
-            .await;
-        assert_ok!(response);
-    }
-
-    #[tokio::test]
-    async fn send_email_fails_if_server_returns_500() {
-        let mock_server = MockServer::start().await;
-        let email_client = email_client(mock_server.uri());
-        Mock::given(any())
-            .respond_with(ResponseTemplate::new(500))
-            .expect(1)
-            .mount(&mock_server)
-            .await;
-
-        let response = email_client
-            .send_email(&email(), &subject(), &body(), &body())
-            .await;
-
-        assert_err!(response);
-    }
-
-    #[tokio::test]
-    async fn send_email_times_out_if_server_takes_too_long() {
-        let mock_server = MockServer::start().await;
-        let email_client = email_client(mock_server.uri());
-
-        Mock::given(any())
-            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(180)))
-            .expect(1)
-            .mount(&mock_server)
-            .await;
-
-        let response = email_client
-            .send_email(&email(), &subject(), &body(), &body())
-            .await;
-
-        assert_err!(response);
-    }
-}
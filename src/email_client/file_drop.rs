@@ -0,0 +1,85 @@
+use super::{to_mime_message, EmailClientError, EmailMessage, EmailTransport};
+use async_trait::async_trait;
+use lettre::{AsyncFileTransport, AsyncTransport, Tokio1Executor};
+use std::path::{Path, PathBuf};
+
+/// Writes every email as an `.eml` file into a directory instead of sending it.
+///
+/// Meant for local development: open the files with any mail client to
+/// check what subscribers would have received.
+pub struct FileDropTransport {
+    directory: PathBuf,
+    mailer: AsyncFileTransport<Tokio1Executor>,
+}
+
+impl FileDropTransport {
+    pub fn new(directory: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        std::fs::create_dir_all(&directory)?;
+        Ok(Self {
+            directory: directory.as_ref().to_owned(),
+            mailer: AsyncFileTransport::new(directory),
+        })
+    }
+}
+
+#[async_trait]
+impl EmailTransport for FileDropTransport {
+    async fn send(&self, message: &EmailMessage<'_>) -> Result<(), EmailClientError> {
+        let email = to_mime_message(message)?;
+        let id = self.mailer.send(email).await?;
+        tracing::info!(
+            "Email written to {}",
+            self.directory.join(format!("{}.eml", id)).display()
+        );
+        Ok(())
+    }
+
+    async fn check(&self) -> Result<(), anyhow::Error> {
+        let metadata = tokio::fs::metadata(&self.directory).await?;
+        if !metadata.is_dir() {
+            anyhow::bail!("{} is not a directory", self.directory.display());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileDropTransport;
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailMessage, EmailTransport};
+    use claims::assert_ok;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn send_writes_an_eml_file_into_the_directory() {
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let transport = FileDropTransport::new(&directory).unwrap();
+        let from = SubscriberEmail::parse("sender@example.com".into()).unwrap();
+        let to = SubscriberEmail::parse("recipient@example.com".into()).unwrap();
+
+        let outcome = transport
+            .send(&EmailMessage {
+                from: &from,
+                to: &to,
+                subject: "Hello there",
+                html_body: "<p>Hello in HTML</p>",
+                text_body: "Hello in plain text",
+                unsubscribe_url: None,
+            })
+            .await;
+        assert_ok!(outcome);
+
+        let files: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        let contents = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(contents.contains("Subject: Hello there"));
+        assert!(contents.contains("To: recipient@example.com"));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,612 @@
+mod file_drop;
+mod postmark;
+mod smtp;
+
+pub use file_drop::FileDropTransport;
+pub use postmark::PostmarkTransport;
+pub use smtp::{SmtpTls, SmtpTransport};
+
+use crate::domain::SubscriberEmail;
+use crate::metrics::METRICS;
+use async_trait::async_trait;
+use lettre::message::header::{HeaderName, HeaderValue};
+use lettre::message::MultiPart;
+use rand::Rng;
+use reqwest::StatusCode;
+use std::time::Duration;
+use url::ParseError;
+
+/// Sends newsletter and transactional emails on behalf of the application.
+///
+/// `EmailClient` knows who the sender is and how to retry, but not how an
+/// email actually leaves the building: that is up to the `EmailTransport`
+/// selected in the configuration.
+pub struct EmailClient {
+    sender: SubscriberEmail,
+    transport: Box<dyn EmailTransport>,
+    retry_policy: RetryPolicy,
+}
+
+/// A fully addressed email, ready to be handed over to a transport.
+pub struct EmailMessage<'a> {
+    pub from: &'a SubscriberEmail,
+    pub to: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_body: &'a str,
+    pub text_body: &'a str,
+    /// Where the recipient can leave the list with a single `POST`.
+    ///
+    /// Transports must surface it as the `List-Unsubscribe` and
+    /// `List-Unsubscribe-Post` headers (RFC 8058).
+    pub unsubscribe_url: Option<&'a str>,
+}
+
+impl EmailMessage<'_> {
+    /// The RFC 8058 headers for this message, if it has an unsubscribe URL.
+    pub fn list_unsubscribe_headers(&self) -> Vec<(&'static str, String)> {
+        match self.unsubscribe_url {
+            Some(url) => vec![
+                ("List-Unsubscribe", format!("<{}>", url)),
+                ("List-Unsubscribe-Post", "List-Unsubscribe=One-Click".into()),
+            ],
+            None => vec![],
+        }
+    }
+}
+
+#[async_trait]
+pub trait EmailTransport: Send + Sync {
+    /// Make a single attempt at delivering `message`.
+    ///
+    /// Retries are handled by `EmailClient`, based on
+    /// `EmailClientError::is_transient`.
+    async fn send(&self, message: &EmailMessage<'_>) -> Result<(), EmailClientError>;
+
+    /// Check that emails could be delivered right now, without sending one.
+    async fn check(&self) -> Result<(), anyhow::Error>;
+}
+
+/// How `EmailClient` retries transient failures.
+///
+/// The n-th retry waits `base_delay * 2^(n-1)`, capped at `max_delay`,
+/// plus a random amount of up to `jitter` so that many failed sends do
+/// not all hit the provider again at the same instant.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: Duration,
+}
+
+impl RetryPolicy {
+    /// The delay before retrying, excluding jitter, after `attempt` has failed.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        self.base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay)
+    }
+
+    /// The longest a send can take, every attempt timing out after
+    /// `attempt_timeout` and every delay getting the whole jitter.
+    pub fn retry_budget(&self, attempt_timeout: Duration) -> Duration {
+        (1..self.max_attempts).fold(
+            attempt_timeout.saturating_mul(self.max_attempts),
+            |budget, attempt| {
+                budget
+                    .saturating_add(self.backoff(attempt))
+                    .saturating_add(self.jitter)
+            },
+        )
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let jitter = if self.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            rand::thread_rng().gen_range(Duration::ZERO..=self.jitter)
+        };
+        self.backoff(attempt) + jitter
+    }
+}
+
+#[derive(Debug)]
+pub enum EmailClientError {
+    UrlParseError(ParseError),
+    Reqwest(reqwest::Error),
+    Address(lettre::address::AddressError),
+    Message(lettre::error::Error),
+    Smtp(lettre::transport::smtp::Error),
+    FileDrop(lettre::transport::file::Error),
+}
+
+impl EmailClientError {
+    /// Whether trying again later has a chance of succeeding.
+    ///
+    /// Timeouts, connection errors, rate limiting and server errors are
+    /// transient. Any other 4xx means the request itself was rejected and
+    /// sending it again would fail the same way.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            EmailClientError::Reqwest(e) => {
+                e.is_timeout()
+                    || e.is_connect()
+                    || e.status().is_some_and(|status| {
+                        status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+                    })
+            }
+            // 4xx SMTP replies are transient by definition. Anything that is
+            // not a reply from the server nor a local problem is a connection
+            // or network failure.
+            EmailClientError::Smtp(e) => {
+                e.is_transient()
+                    || e.is_timeout()
+                    || !(e.is_permanent()
+                        || e.is_response()
+                        || e.is_client()
+                        || e.is_tls()
+                        || e.is_transport_shutdown())
+            }
+            EmailClientError::UrlParseError(_)
+            | EmailClientError::Address(_)
+            | EmailClientError::Message(_)
+            | EmailClientError::FileDrop(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for EmailClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmailClientError::UrlParseError(_) => {
+                write!(f, "Failed to build the email provider URL.")
+            }
+            EmailClientError::Reqwest(_) => {
+                write!(f, "Failed to deliver the email to the email provider.")
+            }
+            EmailClientError::Address(_) => write!(f, "Invalid sender or recipient address."),
+            EmailClientError::Message(_) => write!(f, "Failed to build the email message."),
+            EmailClientError::Smtp(_) => {
+                write!(f, "Failed to deliver the email to the SMTP server.")
+            }
+            EmailClientError::FileDrop(_) => {
+                write!(f, "Failed to write the email to the drop directory.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmailClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmailClientError::UrlParseError(e) => Some(e),
+            EmailClientError::Reqwest(e) => Some(e),
+            EmailClientError::Address(e) => Some(e),
+            EmailClientError::Message(e) => Some(e),
+            EmailClientError::Smtp(e) => Some(e),
+            EmailClientError::FileDrop(e) => Some(e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for EmailClientError {
+    fn from(error: reqwest::Error) -> Self {
+        EmailClientError::Reqwest(error)
+    }
+}
+
+impl From<ParseError> for EmailClientError {
+    fn from(error: ParseError) -> Self {
+        EmailClientError::UrlParseError(error)
+    }
+}
+
+impl From<lettre::address::AddressError> for EmailClientError {
+    fn from(error: lettre::address::AddressError) -> Self {
+        EmailClientError::Address(error)
+    }
+}
+
+impl From<lettre::error::Error> for EmailClientError {
+    fn from(error: lettre::error::Error) -> Self {
+        EmailClientError::Message(error)
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for EmailClientError {
+    fn from(error: lettre::transport::smtp::Error) -> Self {
+        EmailClientError::Smtp(error)
+    }
+}
+
+impl From<lettre::transport::file::Error> for EmailClientError {
+    fn from(error: lettre::transport::file::Error) -> Self {
+        EmailClientError::FileDrop(error)
+    }
+}
+
+/// Render a message as MIME, for the transports that speak it natively.
+fn to_mime_message(message: &EmailMessage<'_>) -> Result<lettre::Message, EmailClientError> {
+    let mut builder = lettre::Message::builder()
+        .from(message.from.as_ref().parse()?)
+        .to(message.to.as_ref().parse()?)
+        .subject(message.subject);
+    for (name, value) in message.list_unsubscribe_headers() {
+        builder = builder.raw_header(HeaderValue::new(
+            HeaderName::new_from_ascii_str(name),
+            value,
+        ));
+    }
+    let email = builder.multipart(MultiPart::alternative_plain_html(
+        message.text_body.to_string(),
+        message.html_body.to_string(),
+    ))?;
+    Ok(email)
+}
+
+impl EmailClient {
+    /// See `EmailTransport::check`.
+    pub async fn check_transport(&self) -> Result<(), anyhow::Error> {
+        self.transport.check().await
+    }
+
+    pub fn new(
+        sender: SubscriberEmail,
+        transport: Box<dyn EmailTransport>,
+        retry_policy: RetryPolicy,
+    ) -> Self {
+        Self {
+            sender,
+            transport,
+            retry_policy,
+        }
+    }
+
+    /// Send an email to `recepient`.
+    ///
+    /// Emails to subscribers must pass their `unsubscribe_url`: a link to it
+    /// is appended to both bodies and the transport adds the
+    /// `List-Unsubscribe` headers. Only emails to administrators, who are not
+    /// on the list, go out without one.
+    #[tracing::instrument(
+        name = "Sending email",
+        skip_all,
+        fields(email.attempts = tracing::field::Empty)
+    )]
+    pub async fn send_email(
+        &self,
+        recepient: &SubscriberEmail,
+        subject: &str,
+        html_body: &str,
+        text_body: &str,
+        unsubscribe_url: Option<&str>,
+    ) -> Result<(), EmailClientError> {
+        let (html_body, text_body) = match unsubscribe_url {
+            Some(url) => (
+                format!(
+                    "{}<br /><br />\
+                    <small>Don't want these emails anymore? \
+                    <a href=\"{}\">Unsubscribe</a></small>",
+                    html_body, url
+                ),
+                format!(
+                    "{}\n\n--\nDon't want these emails anymore? Unsubscribe: {}",
+                    text_body, url
+                ),
+            ),
+            None => (html_body.to_owned(), text_body.to_owned()),
+        };
+        let message = EmailMessage {
+            from: &self.sender,
+            to: recepient,
+            subject,
+            html_body: &html_body,
+            text_body: &text_body,
+            unsubscribe_url,
+        };
+
+        let mut attempt = 1;
+        loop {
+            tracing::Span::current().record("email.attempts", attempt);
+            let timer = METRICS.email_send_duration.start_timer();
+            let outcome = self.transport.send(&message).await;
+            timer.observe_duration();
+            match outcome {
+                Ok(()) => {
+                    METRICS.emails_sent.inc();
+                    return Ok(());
+                }
+                Err(e) if e.is_transient() && attempt < self.retry_policy.max_attempts => {
+                    let delay = self.retry_policy.delay(attempt);
+                    tracing::warn!(
+                        error = ?e,
+                        attempt,
+                        retry_in_ms = delay.as_millis() as u64,
+                        "Transient failure while sending an email, retrying"
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    METRICS.emails_failed.inc();
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmailClient, EmailClientError, PostmarkTransport, RetryPolicy};
+    use crate::domain::SubscriberEmail;
+    use claims::{assert_err, assert_ok};
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::{Fake, Faker};
+    use secrecy::Secret;
+    use std::time::Duration;
+    use wiremock::matchers::{any, header, header_exists, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    struct SendEmailBodyMatcher;
+
+    impl wiremock::Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &wiremock::Request) -> bool {
+            let body: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+            if let Ok(body) = body {
+                body.get("From").is_some()
+                    && body.get("To").is_some()
+                    && body.get("Subject").is_some()
+                    && body.get("TextBody").is_some()
+            } else {
+                false
+            }
+        }
+    }
+
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    fn body() -> String {
+        Paragraph(1..10).fake()
+    }
+
+    fn email_client(base_url: String) -> EmailClient {
+        let transport = PostmarkTransport::new(
+            base_url,
+            Secret::new(Faker.fake()),
+            Duration::from_millis(200),
+        )
+        .unwrap();
+        EmailClient::new(
+            email(),
+            Box::new(transport),
+            RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(50),
+                jitter: Duration::from_millis(5),
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn send_email_fires_a_request_to_base_url() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(header("Content-Type", "application/json"))
+            .and(path("/email"))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let response = email_client
+            .send_email(&email(), &subject(), &body(), &body(), None)
+            .await;
+        assert_ok!(response);
+    }
+
+    #[tokio::test]
+    async fn send_email_with_an_unsubscribe_url_sets_list_unsubscribe_headers() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let unsubscribe_url = "https://example.com/subscriptions/unsubscribe?unsubscribe_token=abc";
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(
+                &email(),
+                &subject(),
+                &body(),
+                &body(),
+                Some(unsubscribe_url),
+            )
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(
+            body["Headers"],
+            serde_json::json!([
+                {"Name": "List-Unsubscribe", "Value": format!("<{}>", unsubscribe_url)},
+                {"Name": "List-Unsubscribe-Post", "Value": "List-Unsubscribe=One-Click"},
+            ])
+        );
+        assert!(body["TextBody"].as_str().unwrap().contains(unsubscribe_url));
+        assert!(body["HtmlBody"].as_str().unwrap().contains(unsubscribe_url));
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_server_keeps_returning_500() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        let response = email_client
+            .send_email(&email(), &subject(), &body(), &body(), None)
+            .await;
+
+        assert_err!(response);
+    }
+
+    #[tokio::test]
+    async fn send_email_times_out_if_server_takes_too_long() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(180)))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        let response = email_client
+            .send_email(&email(), &subject(), &body(), &body(), None)
+            .await;
+
+        assert_err!(response);
+    }
+
+    #[tokio::test]
+    async fn send_email_succeeds_after_a_transient_failure() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let response = email_client
+            .send_email(&email(), &subject(), &body(), &body(), None)
+            .await;
+
+        assert_ok!(response);
+    }
+
+    #[tokio::test]
+    async fn send_email_retries_when_rate_limited() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        let response = email_client
+            .send_email(&email(), &subject(), &body(), &body(), None)
+            .await;
+
+        assert_err!(response);
+    }
+
+    #[tokio::test]
+    async fn send_email_does_not_retry_client_errors() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let response = email_client
+            .send_email(&email(), &subject(), &body(), &body(), None)
+            .await;
+
+        assert_err!(response);
+    }
+
+    #[test]
+    fn an_invalid_postmark_base_url_is_an_error() {
+        let transport = PostmarkTransport::new(
+            "127.0.0.1:8000".into(),
+            Secret::new(Faker.fake()),
+            Duration::from_millis(200),
+        );
+        assert!(matches!(transport, Err(EmailClientError::UrlParseError(_))));
+    }
+
+    #[test]
+    fn backoff_doubles_after_each_attempt_up_to_the_max_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            jitter: Duration::ZERO,
+        };
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(800));
+        assert_eq!(policy.backoff(5), Duration::from_millis(1000));
+        assert_eq!(policy.backoff(100), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn the_retry_budget_covers_every_attempt_and_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            jitter: Duration::from_millis(10),
+        };
+
+        assert_eq!(
+            policy.retry_budget(Duration::from_secs(1)),
+            Duration::from_millis(3_000 + 110 + 210)
+        );
+    }
+
+    #[test]
+    fn jitter_never_exceeds_its_bound() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            jitter: Duration::from_millis(50),
+        };
+
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_millis(100));
+            assert!(delay <= Duration::from_millis(150));
+        }
+    }
+}
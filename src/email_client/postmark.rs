@@ -0,0 +1,100 @@
+use super::{EmailClientError, EmailMessage, EmailTransport};
+use crate::request_id::{RequestId, REQUEST_ID_HEADER};
+use crate::telemetry::inject_trace_context;
+use async_trait::async_trait;
+use reqwest::{Client, Method, RequestBuilder, Url};
+use secrecy::{ExposeSecret, Secret};
+use serde::Serialize;
+use std::time::Duration;
+
+/// Delivers emails through Postmark's HTTP API.
+pub struct PostmarkTransport {
+    http_client: Client,
+    base_url: Url,
+    authorization_token: Secret<String>,
+}
+
+impl PostmarkTransport {
+    pub fn new(
+        base_url: String,
+        authorization_token: Secret<String>,
+        timeout: Duration,
+    ) -> Result<Self, EmailClientError> {
+        Ok(Self {
+            http_client: Client::builder().timeout(timeout).build()?,
+            base_url: Url::parse(&base_url)?,
+            authorization_token,
+        })
+    }
+
+    /// An authenticated request to Postmark, carrying our trace context and
+    /// the id of the request we are handling.
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        let mut request = inject_trace_context(self.http_client.request(method, url)).header(
+            "X-Postmark-Server-Token",
+            self.authorization_token.expose_secret(),
+        );
+        if let Some(request_id) = RequestId::current() {
+            request = request.header(REQUEST_ID_HEADER, request_id.as_str());
+        }
+        request
+    }
+}
+
+#[async_trait]
+impl EmailTransport for PostmarkTransport {
+    async fn send(&self, message: &EmailMessage<'_>) -> Result<(), EmailClientError> {
+        let url = self.base_url.join("/email")?;
+
+        let request_body = SendEmailRequest {
+            from: message.from.as_ref(),
+            to: message.to.as_ref(),
+            subject: message.subject,
+            html_body: message.html_body,
+            text_body: message.text_body,
+            headers: message
+                .list_unsubscribe_headers()
+                .into_iter()
+                .map(|(name, value)| MessageHeader { name, value })
+                .collect(),
+        };
+
+        self.request(Method::POST, url)
+            .json(&request_body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Fetch the settings of the server the token belongs to: it fails if
+    /// Postmark is down or the token was revoked.
+    async fn check(&self) -> Result<(), anyhow::Error> {
+        let url = self.base_url.join("/server")?;
+        self.request(Method::GET, url)
+            .header("Accept", "application/json")
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<MessageHeader>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct MessageHeader {
+    name: &'static str,
+    value: String,
+}
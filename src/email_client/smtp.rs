@@ -0,0 +1,208 @@
+use super::{to_mime_message, EmailClientError, EmailMessage, EmailTransport};
+use async_trait::async_trait;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use secrecy::{ExposeSecret, Secret};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// How the connection to the SMTP server is secured.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    /// Plaintext only. Meant for local SMTP sinks such as MailHog or Mailpit.
+    None,
+    /// Start in plaintext and require an upgrade via `STARTTLS`.
+    Starttls,
+    /// TLS from the first byte, usually on port 465.
+    Implicit,
+}
+
+/// Delivers emails to an SMTP server.
+pub struct SmtpTransport {
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpTransport {
+    pub fn new(
+        host: &str,
+        port: u16,
+        credentials: Option<(String, Secret<String>)>,
+        tls: SmtpTls,
+        timeout: Duration,
+    ) -> Result<Self, EmailClientError> {
+        let tls = match tls {
+            SmtpTls::None => Tls::None,
+            SmtpTls::Starttls => Tls::Required(TlsParameters::new(host.into())?),
+            SmtpTls::Implicit => Tls::Wrapper(TlsParameters::new(host.into())?),
+        };
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+            .port(port)
+            .tls(tls)
+            .timeout(Some(timeout));
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(
+                username,
+                password.expose_secret().to_owned(),
+            ));
+        }
+
+        Ok(Self {
+            mailer: builder.build(),
+        })
+    }
+}
+
+#[async_trait]
+impl EmailTransport for SmtpTransport {
+    async fn send(&self, message: &EmailMessage<'_>) -> Result<(), EmailClientError> {
+        let email = to_mime_message(message)?;
+        self.mailer.send(email).await?;
+        Ok(())
+    }
+
+    /// Open a connection, authenticate if configured, and `NOOP`.
+    async fn check(&self) -> Result<(), anyhow::Error> {
+        if !self.mailer.test_connection().await? {
+            anyhow::bail!("The SMTP server did not answer NOOP");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SmtpTls, SmtpTransport};
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailMessage, EmailTransport};
+    use claims::{assert_err, assert_ok};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// A minimal SMTP sink: accepts a single session and returns the DATA
+    /// section it received, or replies to MAIL FROM with `mail_reply`.
+    async fn smtp_sink(mail_reply: &'static str) -> (u16, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut data = String::new();
+            let mut in_data = false;
+
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if in_data {
+                    if line == "." {
+                        in_data = false;
+                        writer.write_all(b"250 OK queued\r\n").await.unwrap();
+                    } else {
+                        data.push_str(&line);
+                        data.push('\n');
+                    }
+                    continue;
+                }
+                let reply: &[u8] = match line.get(..4).map(|c| c.to_ascii_uppercase()) {
+                    Some(c) if c == "EHLO" || c == "HELO" => b"250 localhost\r\n",
+                    Some(c) if c == "MAIL" => mail_reply.as_bytes(),
+                    Some(c) if c == "RCPT" => b"250 OK\r\n",
+                    Some(c) if c == "DATA" => {
+                        in_data = true;
+                        b"354 End data with <CR><LF>.<CR><LF>\r\n"
+                    }
+                    Some(c) if c == "QUIT" => {
+                        writer.write_all(b"221 Bye\r\n").await.unwrap();
+                        break;
+                    }
+                    _ => b"250 OK\r\n",
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+            data
+        });
+        (port, handle)
+    }
+
+    fn transport(port: u16) -> SmtpTransport {
+        SmtpTransport::new(
+            "127.0.0.1",
+            port,
+            None,
+            SmtpTls::None,
+            Duration::from_secs(1),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn send_delivers_a_multipart_message_to_the_smtp_server() {
+        let (port, sink) = smtp_sink("250 OK\r\n").await;
+        let from = SubscriberEmail::parse("sender@example.com".into()).unwrap();
+        let to = SubscriberEmail::parse("recipient@example.com".into()).unwrap();
+
+        let outcome = transport(port)
+            .send(&EmailMessage {
+                from: &from,
+                to: &to,
+                subject: "Hello there",
+                html_body: "<p>Hello in HTML</p>",
+                text_body: "Hello in plain text",
+                unsubscribe_url: None,
+            })
+            .await;
+        assert_ok!(outcome);
+
+        let data = sink.await.unwrap();
+        assert!(data.contains("Subject: Hello there"));
+        assert!(data.contains("To: recipient@example.com"));
+        assert!(data.contains("Hello in plain text"));
+        assert!(data.contains("<p>Hello in HTML</p>"));
+    }
+
+    #[tokio::test]
+    async fn send_adds_list_unsubscribe_headers_when_there_is_an_unsubscribe_url() {
+        let (port, sink) = smtp_sink("250 OK\r\n").await;
+        let from = SubscriberEmail::parse("sender@example.com".into()).unwrap();
+        let to = SubscriberEmail::parse("recipient@example.com".into()).unwrap();
+
+        let outcome = transport(port)
+            .send(&EmailMessage {
+                from: &from,
+                to: &to,
+                subject: "Hello there",
+                html_body: "<p>Hello in HTML</p>",
+                text_body: "Hello in plain text",
+                unsubscribe_url: Some("https://example.com/unsubscribe"),
+            })
+            .await;
+        assert_ok!(outcome);
+
+        let data = sink.await.unwrap();
+        assert!(data.contains("List-Unsubscribe: <https://example.com/unsubscribe>"));
+        assert!(data.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+    }
+
+    #[tokio::test]
+    async fn send_fails_if_the_server_rejects_the_sender() {
+        let (port, _sink) = smtp_sink("550 Sender rejected\r\n").await;
+        let from = SubscriberEmail::parse("sender@example.com".into()).unwrap();
+        let to = SubscriberEmail::parse("recipient@example.com".into()).unwrap();
+
+        let outcome = transport(port)
+            .send(&EmailMessage {
+                from: &from,
+                to: &to,
+                subject: "Hello there",
+                html_body: "<p>Hello in HTML</p>",
+                text_body: "Hello in plain text",
+                unsubscribe_url: None,
+            })
+            .await;
+
+        assert_err!(outcome);
+    }
+}
@@ -0,0 +1,326 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailClient, EmailClientError};
+use crate::request_id::RequestId;
+use crate::routes::unsubscribe_link;
+use crate::telemetry::Pii;
+use chrono::Utc;
+use sqlx::{Executor, PgPool, Postgres};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+/// How many times a delivery is attempted before it is moved to
+/// `failed_deliveries`.
+pub const MAX_DELIVERY_ATTEMPTS: i16 = 6;
+
+/// The delay before the first new attempt, doubled after each failure:
+/// with 6 attempts, deliveries survive an outage of about two and a half
+/// hours.
+const FIRST_RETRY_DELAY_MINUTES: i64 = 5;
+
+/// How long a claimed delivery is hidden from the other workers. Far longer
+/// than sending an email can take, retries included.
+const LEASE_MINUTES: i64 = 10;
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+struct NewsletterIssue {
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
+/// Drain `issue_delivery_queue`, one recipient at a time, until `shutdown`
+/// is cancelled.
+///
+/// A delivery that fails for a transient reason stays in the queue and is
+/// tried again later, see `retry_later`. One the email provider rejects
+/// outright is moved to `failed_deliveries` straight away.
+///
+/// Cancellation is only checked between tasks, so that no email is sent
+/// without its task being removed from the queue. A delivery that has
+/// started gets the shutdown grace period to finish, which settings
+/// validation keeps longer than the email client's retry budget. If the
+/// database stalls past it anyway, the worker is aborted: the task is tried
+/// again once its lease runs out, which can send that email a second time.
+///
+/// Several workers (in this process or in other instances of the app) can
+/// run side by side: each task is claimed with `FOR UPDATE SKIP LOCKED` and
+/// leased for `LEASE_MINUTES`, so a row is only ever handled by the worker
+/// that claimed it. No lock is held while the email is sent.
+pub async fn run_worker_until_stopped(
+    connection_pool: PgPool,
+    email_client: EmailClient,
+    base_url: String,
+    shutdown: CancellationToken,
+) -> Result<(), anyhow::Error> {
+    while !shutdown.is_cancelled() {
+        let idle = match try_execute_task(&connection_pool, &email_client, &base_url).await {
+            Ok(ExecutionOutcome::EmptyQueue) => Duration::from_secs(10),
+            Err(_) => Duration::from_secs(1),
+            Ok(ExecutionOutcome::TaskCompleted) => continue,
+        };
+        tokio::select! {
+            _ = shutdown.cancelled() => {}
+            _ = tokio::time::sleep(idle) => {}
+        }
+    }
+    Ok(())
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(
+        newsletter_issue_id = tracing::field::Empty,
+        subscriber_email = tracing::field::Empty,
+        x_request_id = tracing::field::Empty
+    ),
+    err
+)]
+pub async fn try_execute_task(
+    connection_pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let task = dequeue_task(connection_pool).await?;
+    let Some(task) = task else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    let DeliveryTask {
+        issue_id,
+        email,
+        n_retries,
+        request_id,
+    } = task;
+    Span::current()
+        .record("newsletter_issue_id", display(issue_id))
+        .record("subscriber_email", display(Pii(&email)));
+
+    let delivery = deliver_issue(
+        connection_pool,
+        email_client,
+        base_url,
+        issue_id,
+        email,
+        n_retries,
+    );
+    // Carry on the id of the request that published the issue, logged under
+    // the same field as on that request.
+    match request_id {
+        Some(request_id) => {
+            Span::current().record("x_request_id", display(&request_id));
+            request_id.scope(delivery).await
+        }
+        None => delivery.await,
+    }
+}
+
+async fn deliver_issue(
+    connection_pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+    issue_id: Uuid,
+    email: String,
+    n_retries: i16,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let Some(unsubscribe_token) = get_unsubscribe_token(connection_pool, &email).await? else {
+        // They left the list after the issue was published.
+        tracing::info!("Skipping a subscriber who is no longer confirmed");
+        delete_task(connection_pool, issue_id, &email).await?;
+        return Ok(ExecutionOutcome::TaskCompleted);
+    };
+
+    match SubscriberEmail::parse(email.clone()) {
+        Ok(email) => {
+            let issue = get_issue(connection_pool, issue_id).await?;
+            if let Err(e) = email_client
+                .send_email(
+                    &email,
+                    &issue.title,
+                    &issue.html_content,
+                    &issue.text_content,
+                    Some(&unsubscribe_link(base_url, &unsubscribe_token)),
+                )
+                .await
+            {
+                let n_retries = n_retries + 1;
+                if e.is_transient() && n_retries < MAX_DELIVERY_ATTEMPTS {
+                    tracing::error!(
+                        error = ?e,
+                        "Failed to deliver issue to a confirmed subscriber. It will be retried.",
+                    );
+                    retry_later(connection_pool, issue_id, email.as_ref(), n_retries).await?;
+                } else {
+                    tracing::error!(
+                        error = ?e,
+                        attempts = n_retries,
+                        "Giving up on delivering issue to a confirmed subscriber",
+                    );
+                    give_up(connection_pool, issue_id, email.as_ref(), n_retries, &e).await?;
+                }
+                return Ok(ExecutionOutcome::TaskCompleted);
+            }
+        }
+        Err(e) => {
+            // The stored email was valid when it was inserted, but our
+            // validation rules may have become stricter since then.
+            tracing::warn!(
+                error = %e,
+                "Skipping a confirmed subscriber. Their stored contact details are invalid",
+            );
+        }
+    }
+    delete_task(connection_pool, issue_id, &email).await?;
+
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+struct DeliveryTask {
+    issue_id: Uuid,
+    email: String,
+    /// How many attempts have already failed.
+    n_retries: i16,
+    /// The request that published the issue.
+    request_id: Option<RequestId>,
+}
+
+/// Claim the next task that is due: it is leased for `LEASE_MINUTES`, and
+/// the claim is committed before the email is sent.
+#[tracing::instrument(skip_all)]
+async fn dequeue_task(connection_pool: &PgPool) -> Result<Option<DeliveryTask>, anyhow::Error> {
+    let r = sqlx::query!(
+        r#"UPDATE issue_delivery_queue
+        SET execute_after = $1
+        WHERE (newsletter_issue_id, subscriber_email) = (
+            SELECT newsletter_issue_id, subscriber_email
+            FROM issue_delivery_queue
+            WHERE execute_after <= now()
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT 1
+        )
+        RETURNING newsletter_issue_id, subscriber_email, n_retries, request_id"#,
+        Utc::now() + chrono::Duration::minutes(LEASE_MINUTES)
+    )
+    .fetch_optional(connection_pool)
+    .await?;
+
+    Ok(r.map(|r| DeliveryTask {
+        issue_id: r.newsletter_issue_id,
+        email: r.subscriber_email,
+        n_retries: r.n_retries,
+        request_id: r.request_id.as_deref().and_then(RequestId::parse),
+    }))
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_task<'c, E>(executor: E, issue_id: Uuid, email: &str) -> Result<(), anyhow::Error>
+where
+    E: Executor<'c, Database = Postgres>,
+{
+    sqlx::query!(
+        r#"DELETE FROM issue_delivery_queue
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2"#,
+        issue_id,
+        email
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Put the task back in the queue for attempt number `n_retries + 1`.
+#[tracing::instrument(skip_all)]
+async fn retry_later(
+    connection_pool: &PgPool,
+    issue_id: Uuid,
+    email: &str,
+    n_retries: i16,
+) -> Result<(), anyhow::Error> {
+    let delay = chrono::Duration::minutes(FIRST_RETRY_DELAY_MINUTES << (n_retries - 1));
+    sqlx::query!(
+        r#"UPDATE issue_delivery_queue
+        SET n_retries = $3, execute_after = $4
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2"#,
+        issue_id,
+        email,
+        n_retries,
+        Utc::now() + delay
+    )
+    .execute(connection_pool)
+    .await?;
+    Ok(())
+}
+
+/// Move the task to `failed_deliveries`, after `n_retries` attempts.
+#[tracing::instrument(skip_all)]
+async fn give_up(
+    connection_pool: &PgPool,
+    issue_id: Uuid,
+    email: &str,
+    n_retries: i16,
+    error: &EmailClientError,
+) -> Result<(), anyhow::Error> {
+    let mut transaction = connection_pool.begin().await?;
+    sqlx::query!(
+        r#"INSERT INTO failed_deliveries (
+            newsletter_issue_id, subscriber_email, n_retries, request_id, last_error, failed_at
+        )
+        SELECT newsletter_issue_id, subscriber_email, $3, request_id, $4, now()
+        FROM issue_delivery_queue
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2"#,
+        issue_id,
+        email,
+        n_retries,
+        error.to_string()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    delete_task(&mut *transaction, issue_id, email).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_issue(
+    connection_pool: &PgPool,
+    issue_id: Uuid,
+) -> Result<NewsletterIssue, anyhow::Error> {
+    let issue = sqlx::query_as!(
+        NewsletterIssue,
+        r#"SELECT title, text_content, html_content
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1"#,
+        issue_id
+    )
+    .fetch_one(connection_pool)
+    .await?;
+    Ok(issue)
+}
+
+/// The unsubscribe token of `email`, if they are still a confirmed subscriber.
+#[tracing::instrument(skip_all)]
+async fn get_unsubscribe_token(
+    connection_pool: &PgPool,
+    email: &str,
+) -> Result<Option<String>, anyhow::Error> {
+    let r = sqlx::query!(
+        r#"SELECT unsubscribe_token
+        FROM subscriptions
+        WHERE email = $1 AND status = 'confirmed'"#,
+        email
+    )
+    .fetch_optional(connection_pool)
+    .await?;
+    Ok(r.map(|r| r.unsubscribe_token))
+}
@@ -1,6 +1,18 @@
+pub mod anti_bot;
+pub mod authentication;
 pub mod configuration;
 pub mod domain;
 pub mod email_client;
+pub mod idempotency;
+pub mod issue_delivery_worker;
+pub mod metrics;
+pub mod rate_limit;
+pub mod request_id;
 pub mod routes;
+pub mod session_state;
+pub mod session_store;
+pub mod shutdown;
 pub mod startup;
+pub mod subscription_email_worker;
 pub mod telemetry;
+pub mod utils;
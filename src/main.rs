@@ -1,15 +1,60 @@
+use std::path::PathBuf;
 use zero2prod::configuration;
 use zero2prod::startup::Application;
 use zero2prod::telemetry;
 
+const USAGE: &str = "Usage: zero2prod [--config <path>]";
+
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    let subscriber = telemetry::get_subscriber("zero2prod".into(), "info".into(), std::io::stdout);
-    telemetry::init_subscriber(subscriber);
+    let config_file = match config_file_from_args() {
+        Ok(config_file) => config_file,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let settings = configuration::get_configuration_with(config_file.as_deref())
+        .expect("Failed to read configuration.");
+    settings
+        .validate()
+        .expect("Failed to validate configuration.");
 
-    let settings = configuration::get_configuration().expect("Failed to read configuration.");
+    let tracer_provider = settings
+        .telemetry
+        .tracer_provider()
+        .expect("Failed to build the OpenTelemetry exporter.");
+    let subscriber = telemetry::get_subscriber(
+        settings.telemetry.service_name.clone(),
+        &settings.telemetry.log,
+        std::io::stdout,
+        tracer_provider.as_ref(),
+    )
+    .expect("Failed to open the log file.");
+    telemetry::init_subscriber(subscriber);
 
     let app = Application::build(settings).await?;
     let _ = app.run_until_stopped().await;
+    // Export the spans that are still buffered.
+    if let Some(tracer_provider) = tracer_provider {
+        if let Err(e) = tracer_provider.shutdown() {
+            eprintln!("Failed to flush the remaining spans: {}", e);
+        }
+    }
     Ok(())
 }
+
+/// The file given with `--config`, which overrides the configuration
+/// directory.
+fn config_file_from_args() -> Result<Option<PathBuf>, String> {
+    let mut config_file = None;
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg != "--config" {
+            return Err(format!("Unexpected argument {:?}.", arg));
+        }
+        let path = args.next().ok_or("--config needs a path.")?;
+        config_file = Some(PathBuf::from(path));
+    }
+    Ok(config_file)
+}
@@ -0,0 +1,181 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, ContentType};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpRequest, HttpResponse};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use std::time::Instant;
+
+/// Every metric the application exposes on `/metrics`.
+///
+/// They are process-wide, like the logs: the HTTP middleware, the
+/// `EmailClient`s of the server and of the delivery worker all record into
+/// the same registry.
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    db_pool_connections: IntGauge,
+    db_pool_idle_connections: IntGauge,
+    pub emails_sent: IntCounter,
+    pub emails_failed: IntCounter,
+    pub email_send_duration: Histogram,
+}
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled."),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time spent handling HTTP requests.",
+            ),
+            &["method", "route"],
+        )
+        .unwrap();
+        let db_pool_connections = IntGauge::new(
+            "db_pool_connections",
+            "Connections currently held by the Postgres pool.",
+        )
+        .unwrap();
+        let db_pool_idle_connections = IntGauge::new(
+            "db_pool_idle_connections",
+            "Idle connections in the Postgres pool.",
+        )
+        .unwrap();
+        let emails_sent =
+            IntCounter::new("emails_sent_total", "Emails accepted by the transport.").unwrap();
+        let emails_failed = IntCounter::new(
+            "emails_failed_total",
+            "Emails that could not be sent, after retries.",
+        )
+        .unwrap();
+        let email_send_duration = Histogram::with_opts(HistogramOpts::new(
+            "email_send_duration_seconds",
+            "Time spent in a single call to the email transport.",
+        ))
+        .unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry
+            .register(Box::new(http_request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_pool_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_pool_idle_connections.clone()))
+            .unwrap();
+        registry.register(Box::new(emails_sent.clone())).unwrap();
+        registry.register(Box::new(emails_failed.clone())).unwrap();
+        registry
+            .register(Box::new(email_send_duration.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            db_pool_connections,
+            db_pool_idle_connections,
+            emails_sent,
+            emails_failed,
+            email_send_duration,
+        }
+    }
+}
+
+/// Count and time every request, labelled by the route that served it.
+pub async fn record_http_metrics(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let response = next.call(req).await?;
+
+    // The route pattern, not the path, so that tokens in the path or query
+    // cannot blow up the number of series.
+    let route = response
+        .request()
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".into());
+    let status = response.status().as_u16().to_string();
+    METRICS
+        .http_requests
+        .with_label_values(&[&method, &route, &status])
+        .inc();
+    METRICS
+        .http_request_duration
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    Ok(response)
+}
+
+pub struct MetricsToken(pub Secret<String>);
+
+/// Serve the metrics in the Prometheus text format.
+///
+/// Scrapers must send the configured token as `Authorization: Bearer`.
+pub async fn metrics(
+    request: HttpRequest,
+    token: web::Data<MetricsToken>,
+    connection_pool: web::Data<PgPool>,
+) -> HttpResponse {
+    if !is_authorized(&request, &token.0) {
+        return HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .finish();
+    }
+
+    METRICS
+        .db_pool_connections
+        .set(connection_pool.size() as i64);
+    METRICS
+        .db_pool_idle_connections
+        .set(connection_pool.num_idle() as i64);
+
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer) {
+        tracing::error!(error = ?e, "Failed to encode metrics");
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok()
+        .content_type(ContentType(
+            prometheus::TEXT_FORMAT
+                .parse()
+                .expect("The Prometheus content type is a valid MIME type"),
+        ))
+        .body(buffer)
+}
+
+fn is_authorized(request: &HttpRequest, token: &Secret<String>) -> bool {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|provided| constant_time_eq(provided, token.expose_secret()))
+}
+
+/// Compare without leaking, through timing, how much of the token matched.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
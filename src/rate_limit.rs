@@ -0,0 +1,314 @@
+use crate::utils::e500;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Past this many tracked keys, the least recently seen one is dropped.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+    /// Its position in `Buckets::by_last_use`.
+    last_use: u64,
+}
+
+/// The buckets, and their keys from the least to the most recently seen.
+#[derive(Default)]
+struct Buckets {
+    by_key: HashMap<String, TokenBucket>,
+    by_last_use: BTreeMap<u64, String>,
+    uses: u64,
+}
+
+#[derive(Debug)]
+pub struct ZeroRefillInterval;
+
+impl std::fmt::Display for ZeroRefillInterval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "A token bucket cannot have a zero refill interval.")
+    }
+}
+
+impl std::error::Error for ZeroRefillInterval {}
+
+/// In-memory token buckets, one per key.
+///
+/// Each bucket holds up to `capacity` tokens and gets one back every
+/// `refill_interval`. A request takes a token, or is told how long to wait.
+///
+/// At most `MAX_TRACKED_KEYS` buckets are kept, so that a flood of distinct
+/// keys cannot exhaust memory: the least recently seen key is forgotten.
+pub struct TokenBuckets {
+    capacity: f64,
+    refill_interval: Duration,
+    max_keys: usize,
+    buckets: Mutex<Buckets>,
+}
+
+impl TokenBuckets {
+    pub fn new(capacity: u32, refill_interval: Duration) -> Result<Self, ZeroRefillInterval> {
+        Self::with_max_keys(capacity, refill_interval, MAX_TRACKED_KEYS)
+    }
+
+    fn with_max_keys(
+        capacity: u32,
+        refill_interval: Duration,
+        max_keys: usize,
+    ) -> Result<Self, ZeroRefillInterval> {
+        if refill_interval.is_zero() {
+            return Err(ZeroRefillInterval);
+        }
+        Ok(Self {
+            capacity: capacity as f64,
+            refill_interval,
+            max_keys,
+            buckets: Mutex::new(Buckets::default()),
+        })
+    }
+
+    /// Take a token for `key`, or return how long until one is available.
+    fn try_acquire(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut guard = self.buckets.lock().unwrap();
+        let buckets = &mut *guard;
+        buckets.uses += 1;
+        let last_use = buckets.uses;
+
+        let bucket = match buckets.by_key.get_mut(key) {
+            Some(bucket) => {
+                buckets.by_last_use.remove(&bucket.last_use);
+                bucket
+            }
+            None => {
+                if buckets.by_key.len() >= self.max_keys {
+                    if let Some((_, oldest)) = buckets.by_last_use.pop_first() {
+                        buckets.by_key.remove(&oldest);
+                    }
+                }
+                buckets.by_key.entry(key.to_owned()).or_insert(TokenBucket {
+                    tokens: self.capacity,
+                    last_refill: now,
+                    last_use,
+                })
+            }
+        };
+        buckets.by_last_use.insert(last_use, key.to_owned());
+        bucket.last_use = last_use;
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(self.refill_interval.mul_f64(1.0 - bucket.tokens))
+        }
+    }
+
+    fn refilled(&self, bucket: &TokenBucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        let refill = elapsed.as_secs_f64() / self.refill_interval.as_secs_f64();
+        (bucket.tokens + refill).min(self.capacity)
+    }
+}
+
+/// Limits on `POST /subscriptions`, by client IP and by target email.
+///
+/// `POST /password/reset` has its own instance, which takes precedence as
+/// resource data.
+pub struct SubscriptionRateLimiter {
+    by_ip: TokenBuckets,
+    by_email: TokenBuckets,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl SubscriptionRateLimiter {
+    pub fn new(by_ip: TokenBuckets, by_email: TokenBuckets, trusted_proxies: Vec<IpAddr>) -> Self {
+        Self {
+            by_ip,
+            by_email,
+            trusted_proxies,
+        }
+    }
+}
+
+/// Reject with a 429 the clients, and the email addresses, that are
+/// subscribed (or sent password reset links) more often than the configured
+/// limits allow.
+pub async fn rate_limit_subscriptions(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let limiter = req
+        .app_data::<web::Data<SubscriptionRateLimiter>>()
+        .cloned()
+        .ok_or_else(|| e500("The subscription rate limiter is not configured"))?;
+    let now = Instant::now();
+
+    let forwarded_for = req
+        .headers()
+        .get("X-Forwarded-For")
+        .and_then(|value| value.to_str().ok());
+    let peer_ip = req.peer_addr().map(|addr| addr.ip());
+    if let Some(ip) = client_ip(peer_ip, forwarded_for, &limiter.trusted_proxies) {
+        if let Err(retry_after) = limiter.by_ip.try_acquire(&ip.to_string(), now) {
+            tracing::warn!(client_ip = %ip, "Rate limited a request by client IP");
+            return Ok(req
+                .into_response(too_many_requests(retry_after))
+                .map_into_right_body());
+        }
+    }
+
+    // The form has to be read to find the email: put it back for the handler.
+    let body = req.extract::<web::Bytes>().await?;
+    let email = target_email(&body);
+    req.set_payload(Payload::from(body));
+    if let Some(email) = email {
+        if let Err(retry_after) = limiter.by_email.try_acquire(&email, now) {
+            tracing::warn!("Rate limited a request by target email");
+            return Ok(req
+                .into_response(too_many_requests(retry_after))
+                .map_into_right_body());
+        }
+    }
+
+    next.call(req).await.map(|res| res.map_into_left_body())
+}
+
+/// The address of the client, skipping over our own reverse proxies.
+///
+/// `X-Forwarded-For` can be forged by anyone, so it is only trusted for
+/// the hops that were appended by a proxy in `trusted_proxies`.
+fn client_ip(
+    peer_ip: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut client_ip = peer_ip?;
+    let Some(forwarded_for) = forwarded_for else {
+        return Some(client_ip);
+    };
+    for hop in forwarded_for.rsplit(',') {
+        if !trusted_proxies.contains(&client_ip) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(ip) => client_ip = ip,
+            Err(_) => break,
+        }
+    }
+    Some(client_ip)
+}
+
+fn target_email(form: &[u8]) -> Option<String> {
+    url::form_urlencoded::parse(form)
+        .find(|(key, _)| key == "email")
+        .map(|(_, email)| email.trim().to_lowercase())
+        .filter(|email| !email.is_empty())
+}
+
+fn too_many_requests(retry_after: Duration) -> HttpResponse {
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, HeaderValue::from(seconds.max(1))))
+        .body("Too many requests. Please try again later.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_ip, target_email, TokenBuckets};
+    use claims::{assert_err, assert_ok};
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn a_bucket_allows_a_burst_up_to_its_capacity() {
+        let buckets = TokenBuckets::new(3, Duration::from_secs(60)).unwrap();
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_ok!(buckets.try_acquire("key", now));
+        }
+        let retry_after = assert_err!(buckets.try_acquire("key", now));
+        assert_eq!(retry_after, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn a_bucket_refills_over_time() {
+        let buckets = TokenBuckets::new(1, Duration::from_secs(60)).unwrap();
+        let now = Instant::now();
+        assert_ok!(buckets.try_acquire("key", now));
+
+        let retry_after = assert_err!(buckets.try_acquire("key", now + Duration::from_secs(15)));
+        assert_eq!(retry_after, Duration::from_secs(45));
+        assert_ok!(buckets.try_acquire("key", now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn keys_have_separate_buckets() {
+        let buckets = TokenBuckets::new(1, Duration::from_secs(60)).unwrap();
+        let now = Instant::now();
+        assert_ok!(buckets.try_acquire("a", now));
+        assert_ok!(buckets.try_acquire("b", now));
+        assert_err!(buckets.try_acquire("a", now));
+    }
+
+    #[test]
+    fn the_least_recently_seen_key_is_forgotten_past_the_limit() {
+        let buckets = TokenBuckets::with_max_keys(1, Duration::from_secs(60), 2).unwrap();
+        let now = Instant::now();
+        assert_ok!(buckets.try_acquire("a", now));
+        assert_ok!(buckets.try_acquire("b", now));
+        assert_err!(buckets.try_acquire("a", now));
+
+        assert_ok!(buckets.try_acquire("c", now));
+
+        let tracked = buckets.buckets.lock().unwrap().by_key.len();
+        assert_eq!(tracked, 2);
+        assert_err!(buckets.try_acquire("a", now));
+        // "b" was forgotten and starts from a full bucket again
+        assert_ok!(buckets.try_acquire("b", now));
+    }
+
+    #[test]
+    fn a_zero_refill_interval_is_rejected() {
+        assert!(TokenBuckets::new(1, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_when_the_peer_is_not_a_trusted_proxy() {
+        let client = client_ip(Some(ip("203.0.113.7")), Some("198.51.100.1"), &[]);
+        assert_eq!(client, Some(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn forwarded_for_is_followed_through_trusted_proxies_only() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        // Spoofed hop, real client, then our two proxies
+        let client = client_ip(
+            Some(ip("10.0.0.1")),
+            Some("1.2.3.4, 198.51.100.1, 10.0.0.2"),
+            &trusted,
+        );
+        assert_eq!(client, Some(ip("198.51.100.1")));
+    }
+
+    #[test]
+    fn target_email_is_normalised() {
+        assert_eq!(
+            target_email(b"name=Ursula&email=%20Ursula%40Example.com%20"),
+            Some("ursula@example.com".to_string())
+        );
+        assert_eq!(target_email(b"name=Ursula&email="), None);
+        assert_eq!(target_email(b"name=Ursula"), None);
+    }
+}
@@ -0,0 +1,121 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::HttpMessage;
+use std::future::Future;
+use tracing::Span;
+use tracing_actix_web::{DefaultRootSpanBuilder, RootSpanBuilder};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longer ids sent by clients are replaced rather than trusted.
+const MAX_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static CURRENT_REQUEST_ID: RequestId;
+}
+
+/// Identifies a request in our logs, in its response, in the calls it makes
+/// to the email provider and in the newsletter deliveries it queues.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    fn generate() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    /// Accept an id set by a client or a proxy if it is short and only made
+    /// of visible ASCII characters.
+    pub fn parse(s: &str) -> Option<Self> {
+        let valid =
+            !s.is_empty() && s.len() <= MAX_LENGTH && s.bytes().all(|b| b.is_ascii_graphic());
+        valid.then(|| Self(s.to_owned()))
+    }
+
+    /// The id of the request, or queued delivery, being handled.
+    pub fn current() -> Option<Self> {
+        CURRENT_REQUEST_ID.try_with(Clone::clone).ok()
+    }
+
+    /// Run `f` on behalf of the request with this id.
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CURRENT_REQUEST_ID.scope(self, f).await
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Take the request id from `X-Request-Id`, or generate one, and echo it in
+/// the response.
+///
+/// It must wrap `TracingLogger`, which records the id on the root span.
+pub async fn assign_request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(RequestId::parse)
+        .unwrap_or_else(RequestId::generate);
+    req.extensions_mut().insert(request_id.clone());
+
+    let mut response = request_id.clone().scope(next.call(req)).await?;
+    response.headers_mut().insert(
+        HeaderName::from_static(REQUEST_ID_HEADER),
+        HeaderValue::from_str(request_id.as_str()).expect("Request ids are visible ASCII"),
+    );
+    Ok(response)
+}
+
+/// `TracingLogger`'s root span, with the id set by `assign_request_id`
+/// recorded as `x_request_id`.
+///
+/// The macro always records an id of its own as `request_id`, which cannot
+/// be left out or overwritten: ours gets a field of its own.
+pub struct RequestIdRootSpanBuilder;
+
+impl RootSpanBuilder for RequestIdRootSpanBuilder {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        // Not borrowed across the macro, which needs the extensions mutably.
+        let request_id = request
+            .extensions()
+            .get::<RequestId>()
+            .map(ToString::to_string)
+            .unwrap_or_default();
+        tracing_actix_web::root_span!(request, x_request_id = %request_id)
+    }
+
+    fn on_request_end<B: MessageBody>(
+        span: Span,
+        outcome: &Result<ServiceResponse<B>, actix_web::Error>,
+    ) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestId;
+
+    #[test]
+    fn ids_from_clients_are_only_reused_when_reasonable() {
+        assert!(RequestId::parse("signup-4f2a").is_some());
+        assert!(RequestId::parse("").is_none());
+        assert!(RequestId::parse("two words").is_none());
+        assert!(RequestId::parse("line\nbreak").is_none());
+        assert!(RequestId::parse(&"a".repeat(129)).is_none());
+    }
+}
@@ -0,0 +1,71 @@
+use crate::authentication::{self, validate_credentials, AuthError, UserId};
+use crate::domain::{Credentials, NewPassword};
+use crate::routes::admin::get_username;
+use crate::session_state::TypedSession;
+use crate::utils::{e500, see_other};
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+
+#[derive(serde::Deserialize)]
+pub struct FormData {
+    current_password: Secret<String>,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>,
+}
+
+#[tracing::instrument(
+    name = "Change password",
+    skip(form, connection_pool, user_id, session),
+    fields(user_id = %*user_id)
+)]
+pub async fn change_password(
+    form: web::Form<FormData>,
+    connection_pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+    session: TypedSession,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = user_id.into_inner();
+
+    if form.new_password.expose_secret() != form.new_password_check.expose_secret() {
+        return Ok(password_redirect(
+            "You entered two different new passwords - the field values must match.",
+        ));
+    }
+
+    let new_password = match NewPassword::parse(form.0.new_password) {
+        Ok(password) => password,
+        Err(e) => return Ok(password_redirect(&e)),
+    };
+
+    let username = get_username(*user_id, &connection_pool)
+        .await
+        .map_err(e500)?;
+    let credentials = Credentials {
+        username,
+        password: form.0.current_password,
+    };
+    match validate_credentials(credentials, &connection_pool).await {
+        Ok(_) => {}
+        Err(AuthError::InvalidCredentials) => {
+            return Ok(password_redirect("The current password is incorrect."));
+        }
+        Err(e) => return Err(e500(e)),
+    }
+
+    authentication::change_password(*user_id, new_password, &connection_pool)
+        .await
+        .map_err(e500)?;
+    // Every session of theirs is gone, this one included: carry on under a
+    // new key.
+    session.renew();
+
+    FlashMessage::info("Your password has been changed.").send();
+    Ok(see_other("/admin/password"))
+}
+
+fn password_redirect(message: &str) -> HttpResponse {
+    FlashMessage::error(message).send();
+    see_other("/admin/password")
+}
@@ -1,6 +1,139 @@
-use actix_files::NamedFile;
+use crate::configuration::{EmailProviderCheck, HealthSettings};
+use crate::email_client::EmailClient;
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
 
-pub async fn health_check() -> actix_web::Result<NamedFile> {
-    let path = "static/health_check.html";
-    Ok(NamedFile::open(path)?)
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Up,
+    Down,
+}
+
+#[derive(Serialize)]
+struct ComponentHealth {
+    status: Status,
+    /// Whether the application is ready only when this component is up.
+    required: bool,
+}
+
+#[derive(Serialize)]
+struct HealthReport {
+    status: Status,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    components: BTreeMap<&'static str, ComponentHealth>,
+}
+
+/// The last status of the email provider, so that frequent readiness probes
+/// do not each make a call to it.
+pub struct EmailProviderHealth {
+    ttl: Duration,
+    last_check: Mutex<Option<(Instant, Status)>>,
+}
+
+impl EmailProviderHealth {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            last_check: Mutex::new(None),
+        }
+    }
+}
+
+/// Whether the process is able to answer requests at all.
+///
+/// It checks nothing else: restarting the process would not fix a database
+/// outage, so that belongs in `/health/ready`.
+pub async fn health_live() -> HttpResponse {
+    HttpResponse::Ok().json(HealthReport {
+        status: Status::Up,
+        components: BTreeMap::new(),
+    })
+}
+
+/// Whether the process can serve traffic: Postgres answers, and so does the
+/// email provider if it is configured to be checked.
+///
+/// Returns a 503 when a required component is down. Why a check failed is
+/// only logged: the endpoint is public.
+#[tracing::instrument(name = "Checking readiness", skip_all)]
+pub async fn health_ready(
+    connection_pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    email_provider_health: web::Data<EmailProviderHealth>,
+    settings: web::Data<HealthSettings>,
+) -> HttpResponse {
+    let mut components = BTreeMap::new();
+    let database = check(&settings, "database", async {
+        sqlx::query("SELECT 1")
+            .execute(connection_pool.get_ref())
+            .await?;
+        Ok(())
+    })
+    .await;
+    components.insert(
+        "database",
+        ComponentHealth {
+            status: database,
+            required: true,
+        },
+    );
+    if settings.email_provider != EmailProviderCheck::Disabled {
+        // Held during the check, so that concurrent probes share its result.
+        let mut last_check = email_provider_health.last_check.lock().await;
+        let status = match *last_check {
+            Some((checked_at, status)) if checked_at.elapsed() < email_provider_health.ttl => {
+                status
+            }
+            _ => {
+                let status =
+                    check(&settings, "email_provider", email_client.check_transport()).await;
+                *last_check = Some((Instant::now(), status));
+                status
+            }
+        };
+        components.insert(
+            "email_provider",
+            ComponentHealth {
+                status,
+                required: settings.email_provider == EmailProviderCheck::Required,
+            },
+        );
+    }
+
+    let ready = components
+        .values()
+        .all(|component| !component.required || component.status == Status::Up);
+    let report = HealthReport {
+        status: if ready { Status::Up } else { Status::Down },
+        components,
+    };
+    if ready {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+async fn check(
+    settings: &HealthSettings,
+    component: &'static str,
+    probe: impl Future<Output = Result<(), anyhow::Error>>,
+) -> Status {
+    match tokio::time::timeout(settings.timeout(), probe).await {
+        Ok(Ok(())) => Status::Up,
+        Ok(Err(e)) => {
+            tracing::warn!(component, error.cause_chain = ?e, "A readiness check failed");
+            Status::Down
+        }
+        Err(_) => {
+            tracing::warn!(component, "A readiness check timed out");
+            Status::Down
+        }
+    }
 }
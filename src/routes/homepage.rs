@@ -1,6 +1,13 @@
-use actix_files::NamedFile;
+use crate::{anti_bot::FormTimestampSigner, routes::bot_check_fields_html};
+use actix_web::{http::header::ContentType, web, HttpResponse};
 
-pub async fn homepage() -> actix_web::Result<NamedFile> {
-    let path = "static/homepage.html";
-    Ok(NamedFile::open(path)?)
+const HOMEPAGE_TEMPLATE: &str = include_str!("../../static/homepage.html");
+
+pub async fn homepage(form_signer: web::Data<FormTimestampSigner>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(HOMEPAGE_TEMPLATE.replace(
+            "{{ bot_check_fields }}",
+            &bot_check_fields_html(&form_signer),
+        ))
 }
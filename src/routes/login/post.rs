@@ -0,0 +1,60 @@
+use crate::authentication::{validate_credentials, AuthError};
+use crate::domain::Credentials;
+use crate::session_state::TypedSession;
+use crate::telemetry::Pii;
+use crate::utils::see_other;
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use secrecy::Secret;
+use sqlx::PgPool;
+
+#[derive(serde::Deserialize)]
+pub struct FormData {
+    username: String,
+    password: Secret<String>,
+}
+
+#[tracing::instrument(
+    skip(form, connection_pool, session),
+    fields(username = tracing::field::Empty, user_id = tracing::field::Empty)
+)]
+pub async fn login(
+    form: web::Form<FormData>,
+    connection_pool: web::Data<PgPool>,
+    session: TypedSession,
+) -> HttpResponse {
+    let credentials = Credentials {
+        username: form.0.username,
+        password: form.0.password,
+    };
+    tracing::Span::current().record(
+        "username",
+        tracing::field::display(Pii(&credentials.username)),
+    );
+
+    let user_id = match validate_credentials(credentials, &connection_pool).await {
+        Ok(user_id) => user_id,
+        Err(AuthError::InvalidCredentials) => {
+            return login_redirect("Authentication failed");
+        }
+        Err(e) => {
+            tracing::error!(error = ?e, "Failed to validate credentials");
+            return login_redirect("Something went wrong. Please try again");
+        }
+    };
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+
+    // Rotate the session key on privilege change to prevent session fixation.
+    session.renew();
+    if let Err(e) = session.insert_user_id(user_id) {
+        tracing::error!(error = ?e, "Failed to store the user id in the session");
+        return login_redirect("Something went wrong. Please try again");
+    }
+
+    see_other("/admin/dashboard")
+}
+
+fn login_redirect(message: &str) -> HttpResponse {
+    FlashMessage::error(message).send();
+    see_other("/login")
+}
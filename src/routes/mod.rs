@@ -1,9 +1,19 @@
+pub mod admin;
 pub mod health_check;
 pub mod homepage;
+pub mod login;
+pub mod newsletters;
+pub mod password_reset;
 pub mod subscriptions;
 pub mod subscriptions_confirm;
+pub mod subscriptions_unsubscribe;
 
+pub use admin::*;
 pub use health_check::*;
 pub use homepage::*;
+pub use login::*;
+pub use newsletters::*;
+pub use password_reset::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use subscriptions_unsubscribe::*;
@@ -0,0 +1,203 @@
+use crate::{
+    authentication::{validate_credentials, AuthError},
+    domain::Credentials,
+    idempotency::{save_response, try_processing, IdempotencyKey, NextAction},
+    request_id::RequestId,
+    telemetry::Pii,
+    utils::e500,
+};
+use actix_web::{
+    http::header::{self, HeaderMap, HeaderValue},
+    web, HttpRequest, HttpResponse,
+};
+use base64::Engine;
+use secrecy::Secret;
+use serde::Deserialize;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+#[derive(Deserialize)]
+pub struct BodyData {
+    title: String,
+    content: Content,
+}
+
+#[derive(Deserialize)]
+pub struct Content {
+    html: String,
+    text: String,
+}
+
+#[tracing::instrument(
+    name = "Publishing a newsletter issue",
+    skip(body, connection_pool, request, request_id),
+    fields(
+        newsletter_title = %body.title,
+        username = tracing::field::Empty,
+        user_id = tracing::field::Empty
+    )
+)]
+pub async fn publish_newsletter(
+    body: web::Json<BodyData>,
+    connection_pool: web::Data<PgPool>,
+    request: HttpRequest,
+    request_id: web::ReqData<RequestId>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let credentials = match basic_authentication(request.headers()) {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            tracing::warn!("Rejected a request without valid basic auth: {}", e);
+            return Ok(unauthorized());
+        }
+    };
+    tracing::Span::current().record(
+        "username",
+        tracing::field::display(Pii(&credentials.username)),
+    );
+
+    let user_id = match validate_credentials(credentials, &connection_pool).await {
+        Ok(user_id) => user_id,
+        Err(AuthError::InvalidCredentials) => {
+            return Ok(unauthorized());
+        }
+        Err(e) => return Err(e500(e)),
+    };
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+
+    let idempotency_key: IdempotencyKey = match idempotency_key(request.headers()) {
+        Ok(key) => key,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+    };
+    let mut transaction = match try_processing(&connection_pool, &idempotency_key, user_id)
+        .await
+        .map_err(e500)?
+    {
+        NextAction::StartProcessing(transaction) => transaction,
+        NextAction::ReturnSavedResponse(saved_response) => {
+            return Ok(saved_response);
+        }
+    };
+
+    let issue_id = insert_newsletter_issue(
+        &mut transaction,
+        &body.title,
+        &body.content.text,
+        &body.content.html,
+    )
+    .await
+    .map_err(e500)?;
+    enqueue_delivery_tasks(&mut transaction, issue_id, &request_id)
+        .await
+        .map_err(e500)?;
+
+    let response = HttpResponse::Ok().finish();
+    let response = save_response(transaction, &idempotency_key, user_id, response)
+        .await
+        .map_err(e500)?;
+    Ok(response)
+}
+
+fn idempotency_key(headers: &HeaderMap) -> Result<IdempotencyKey, String> {
+    headers
+        .get(IDEMPOTENCY_KEY)
+        .ok_or("The 'Idempotency-Key' header was missing")?
+        .to_str()
+        .map_err(|_| "The 'Idempotency-Key' header was not a valid UTF8 string")?
+        .to_string()
+        .try_into()
+}
+
+fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static(r#"Basic realm="publish""#),
+        ))
+        .finish()
+}
+
+fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, String> {
+    let header_value = headers
+        .get(header::AUTHORIZATION)
+        .ok_or("The 'Authorization' header was missing")?
+        .to_str()
+        .map_err(|_| "The 'Authorization' header was not a valid UTF8 string")?;
+    let base64_encoded_segment = header_value
+        .strip_prefix("Basic ")
+        .ok_or("The authorization scheme was not 'Basic'")?;
+    let decoded_bytes = base64::engine::general_purpose::STANDARD
+        .decode(base64_encoded_segment)
+        .map_err(|_| "Failed to base64-decode 'Basic' credentials")?;
+    let decoded_credentials = String::from_utf8(decoded_bytes)
+        .map_err(|_| "The decoded credential string is not valid UTF8")?;
+
+    // Split into two segments, using ':' as delimiter
+    let (username, password) = decoded_credentials
+        .split_once(':')
+        .ok_or("A username and a password must both be provided in 'Basic' auth")?;
+
+    Ok(Credentials {
+        username: username.to_string(),
+        password: Secret::new(password.to_string()),
+    })
+}
+
+#[tracing::instrument(skip_all)]
+async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    title: &str,
+    text_content: &str,
+    html_content: &str,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    let query = sqlx::query!(
+        r#"INSERT INTO newsletter_issues (
+            newsletter_issue_id,
+            title,
+            text_content,
+            html_content,
+            published_at
+        )
+        VALUES ($1, $2, $3, $4, now())"#,
+        newsletter_issue_id,
+        title,
+        text_content,
+        html_content
+    );
+    transaction.execute(query).await.map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(newsletter_issue_id)
+}
+
+/// Add one row per confirmed subscriber to the delivery queue.
+///
+/// Delivery itself happens in `issue_delivery_worker`, outside of the request:
+/// the rows keep the id of the request, for the worker to carry on.
+#[tracing::instrument(skip_all)]
+async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    request_id: &RequestId,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"INSERT INTO issue_delivery_queue (
+            newsletter_issue_id,
+            subscriber_email,
+            request_id
+        )
+        SELECT $1, email, $2
+        FROM subscriptions
+        WHERE status = 'confirmed'"#,
+        newsletter_issue_id,
+        request_id.as_str(),
+    );
+    transaction.execute(query).await.map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
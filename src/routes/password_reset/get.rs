@@ -0,0 +1,90 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use std::fmt::Write;
+
+#[derive(serde::Deserialize)]
+pub struct Parameters {
+    password_reset_token: String,
+}
+
+fn flash_messages_html(flash_messages: IncomingFlashMessages) -> String {
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", m.content()).unwrap();
+    }
+    msg_html
+}
+
+pub async fn password_reset_form(flash_messages: IncomingFlashMessages) -> HttpResponse {
+    let msg_html = flash_messages_html(flash_messages);
+
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Reset password</title>
+</head>
+
+<body>
+    {msg_html}
+    <form action="/password/reset" method="post">
+        <label>Email
+            <input type="text" placeholder="Enter the email of your account" name="email">
+        </label>
+        <button type="submit">Send reset link</button>
+    </form>
+    <p><a href="/login">&lt;- Back to login</a></p>
+</body>
+
+</html>"#,
+        ))
+}
+
+pub async fn password_reset_confirm_form(
+    parameters: web::Query<Parameters>,
+    flash_messages: IncomingFlashMessages,
+) -> HttpResponse {
+    let msg_html = flash_messages_html(flash_messages);
+    // Tokens are alphanumeric, but they come from the query string:
+    // make sure nothing else ends up inside the HTML attribute.
+    let token: String = parameters
+        .password_reset_token
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect();
+
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Choose a new password</title>
+</head>
+
+<body>
+    {msg_html}
+    <form action="/password/reset/confirm" method="post">
+        <input hidden type="text" name="password_reset_token" value="{token}">
+        <label>New password
+            <input type="password" placeholder="Enter new password" name="new_password">
+        </label>
+        <br>
+        <label>Confirm new password
+            <input type="password" placeholder="Type the new password again" name="new_password_check">
+        </label>
+        <br>
+        <button type="submit">Reset password</button>
+    </form>
+</body>
+
+</html>"#,
+        ))
+}
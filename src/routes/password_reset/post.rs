@@ -0,0 +1,281 @@
+use crate::authentication::change_password;
+use crate::domain::{NewPassword, SubscriberEmail};
+use crate::email_client::{EmailClient, EmailClientError};
+use crate::request_id::RequestId;
+use crate::shutdown::InFlightRequests;
+use crate::startup::ApplicationBaseUrl;
+use crate::telemetry::PiiRedaction;
+use crate::utils::{e500, generate_token, see_other};
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use chrono::Utc;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use std::sync::Arc;
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// How long a password reset link stays valid after it has been issued.
+const PASSWORD_RESET_TOKEN_TTL_MINUTES: i64 = 60;
+
+#[derive(serde::Deserialize)]
+pub struct RequestFormData {
+    email: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ConfirmFormData {
+    password_reset_token: String,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>,
+}
+
+#[tracing::instrument(
+    name = "Requesting a password reset",
+    skip(form, connection_pool, email_client, base_url, in_flight_requests)
+)]
+pub async fn request_password_reset(
+    form: web::Form<RequestFormData>,
+    connection_pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    in_flight_requests: web::Data<InFlightRequests>,
+) -> Result<HttpResponse, actix_web::Error> {
+    // The response must not reveal whether an account with this email
+    // exists, not even through how long it takes: the link is issued off
+    // the request path. It still runs on behalf of the request, which
+    // shutdown waits for until the link is out.
+    let issue_link = issue_password_reset_link(
+        form.0.email,
+        connection_pool.into_inner(),
+        email_client.into_inner(),
+        base_url.into_inner(),
+    )
+    .instrument(tracing::Span::current());
+    let request_id = RequestId::current();
+    let issue_link = PiiRedaction::current().scope(async move {
+        match request_id {
+            Some(request_id) => request_id.scope(issue_link).await,
+            None => issue_link.await,
+        }
+    });
+    tokio::spawn(in_flight_requests.track(issue_link));
+
+    FlashMessage::info(
+        "If an account with that email exists, we have sent it a link to reset the password.",
+    )
+    .send();
+    Ok(see_other("/password/reset"))
+}
+
+#[tracing::instrument(
+    name = "Confirming a password reset",
+    skip(form, connection_pool),
+    fields(user_id = tracing::field::Empty)
+)]
+pub async fn confirm_password_reset(
+    form: web::Form<ConfirmFormData>,
+    connection_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let form = form.0;
+    // The token is echoed back into a header: keep only the characters
+    // that `generate_token` can produce.
+    let token: String = form
+        .password_reset_token
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect();
+    let retry_location = format!("/password/reset/confirm?password_reset_token={}", token);
+
+    if form.new_password.expose_secret() != form.new_password_check.expose_secret() {
+        FlashMessage::error(
+            "You entered two different new passwords - the field values must match.",
+        )
+        .send();
+        return Ok(see_other(&retry_location));
+    }
+
+    let new_password = match NewPassword::parse(form.new_password) {
+        Ok(password) => password,
+        Err(e) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other(&retry_location));
+        }
+    };
+
+    let user_id = match consume_password_reset_token(&token, &connection_pool)
+        .await
+        .map_err(e500)?
+    {
+        Some(user_id) => user_id,
+        None => {
+            FlashMessage::error("This password reset link is invalid or has expired.").send();
+            return Ok(see_other("/password/reset"));
+        }
+    };
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+
+    change_password(user_id, new_password, &connection_pool)
+        .await
+        .map_err(e500)?;
+    // Links sent before this one must not be able to change it again.
+    delete_password_reset_tokens(user_id, &connection_pool)
+        .await
+        .map_err(e500)?;
+
+    FlashMessage::info("Your password has been reset. You can now log in.").send();
+    Ok(see_other("/login"))
+}
+
+/// Send a reset link to the account registered with `email`, if any.
+async fn issue_password_reset_link(
+    email: String,
+    connection_pool: Arc<PgPool>,
+    email_client: Arc<EmailClient>,
+    base_url: Arc<ApplicationBaseUrl>,
+) {
+    let user_id = match get_user_id_by_email(&email, &connection_pool).await {
+        Ok(user_id) => user_id,
+        // Already logged by the query.
+        Err(_) => return,
+    };
+    let (Some(user_id), Ok(email)) = (user_id, SubscriberEmail::parse(email)) else {
+        return;
+    };
+    let Ok(password_reset_token) = store_password_reset_token(user_id, &connection_pool).await
+    else {
+        return;
+    };
+    if let Err(e) =
+        send_password_reset_email(&email_client, &email, &base_url.0, &password_reset_token).await
+    {
+        tracing::error!(error = ?e, "Failed to send the password reset email");
+    }
+}
+
+#[tracing::instrument(
+    name = "Sending password reset email",
+    skip(email_client, recipient, base_url, password_reset_token)
+)]
+async fn send_password_reset_email(
+    email_client: &EmailClient,
+    recipient: &SubscriberEmail,
+    base_url: &str,
+    password_reset_token: &str,
+) -> Result<(), EmailClientError> {
+    let reset_link = format!(
+        "{}/password/reset/confirm?password_reset_token={}",
+        base_url, password_reset_token
+    );
+
+    let plain_body = format!(
+        "Somebody asked to reset the password of your account.\n\
+        Visit {} to choose a new one. The link expires in {} minutes.\n\
+        If it wasn't you, you can safely ignore this email.",
+        reset_link, PASSWORD_RESET_TOKEN_TTL_MINUTES
+    );
+    let html_body = format!(
+        "Somebody asked to reset the password of your account.<br />\
+        Click <a href=\"{}\">here</a> to choose a new one. The link expires in {} minutes.<br />\
+        If it wasn't you, you can safely ignore this email.",
+        reset_link, PASSWORD_RESET_TOKEN_TTL_MINUTES
+    );
+
+    // Sent to an administrator, not to somebody on the list.
+    email_client
+        .send_email(
+            recipient,
+            "Reset your password",
+            &html_body,
+            &plain_body,
+            None,
+        )
+        .await
+}
+
+#[tracing::instrument(name = "Get user id by email", skip(email, connection_pool))]
+async fn get_user_id_by_email(
+    email: &str,
+    connection_pool: &PgPool,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let result = sqlx::query!(r#"SELECT user_id FROM users WHERE email = $1"#, email)
+        .fetch_optional(connection_pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+
+    Ok(result.map(|r| r.user_id))
+}
+
+#[tracing::instrument(
+    name = "Storing password reset token in db",
+    skip(user_id, connection_pool)
+)]
+async fn store_password_reset_token(
+    user_id: Uuid,
+    connection_pool: &PgPool,
+) -> Result<String, sqlx::Error> {
+    let password_reset_token = generate_token();
+    sqlx::query!(
+        r#"INSERT INTO password_reset_tokens (password_reset_token, user_id, expires_at)
+        VALUES ($1, $2, $3)"#,
+        password_reset_token,
+        user_id,
+        Utc::now() + chrono::Duration::minutes(PASSWORD_RESET_TOKEN_TTL_MINUTES)
+    )
+    .execute(connection_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(password_reset_token)
+}
+
+/// Delete the token and return the user it belongs to, if it has not expired.
+///
+/// The token is removed in the same statement that reads it, so two
+/// concurrent submissions of the same link cannot both succeed.
+#[tracing::instrument(name = "Consuming password reset token", skip(token, connection_pool))]
+async fn consume_password_reset_token(
+    token: &str,
+    connection_pool: &PgPool,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"DELETE FROM password_reset_tokens
+        WHERE password_reset_token = $1
+        RETURNING user_id, expires_at"#,
+        token
+    )
+    .fetch_optional(connection_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(result
+        .filter(|r| r.expires_at > Utc::now())
+        .map(|r| r.user_id))
+}
+
+#[tracing::instrument(name = "Deleting password reset tokens", skip(connection_pool))]
+async fn delete_password_reset_tokens(
+    user_id: Uuid,
+    connection_pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM password_reset_tokens WHERE user_id = $1"#,
+        user_id
+    )
+    .execute(connection_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
@@ -1,152 +1,421 @@
 use crate::{
+    anti_bot::{BotCheckFailure, FormTimestampSigner},
     domain::{NewSubscriber, SubscriberEmail, SubscriberName},
-    email_client::{EmailClient, EmailClientError},
-    startup::ApplicationBaseUrl,
+    request_id::RequestId,
+    subscription_email_worker::{enqueue_subscription_email, SubscriptionEmail},
+    telemetry::Pii,
+    utils::{error_chain_fmt, escape_html, generate_token, prefers_html},
+};
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpRequest, HttpResponse, ResponseError,
 };
-use actix_web::{web, HttpResponse};
 use chrono::Utc;
-use rand::{distributions::Alphanumeric, Rng};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::fmt::Write;
 use uuid::Uuid;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct FormData {
     name: String,
     email: String,
+    /// Hidden from humans: only bots fill it in.
+    #[serde(default)]
+    website: String,
+    form_timestamp: Option<String>,
+    form_signature: Option<String>,
+}
+
+impl FormData {
+    /// Tell apart submissions made by bots from those made by people.
+    fn check_not_a_bot(
+        &self,
+        signer: &FormTimestampSigner,
+        now: i64,
+    ) -> Result<(), BotCheckFailure> {
+        if !self.website.is_empty() {
+            return Err(BotCheckFailure::HoneypotFilled);
+        }
+        let (Some(timestamp), Some(signature)) = (&self.form_timestamp, &self.form_signature)
+        else {
+            return Err(BotCheckFailure::MissingTimestamp);
+        };
+        let timestamp = timestamp
+            .parse()
+            .map_err(|_| BotCheckFailure::InvalidSignature)?;
+        signer.verify(timestamp, signature, now)
+    }
+}
+
+/// The hidden fields every rendering of the subscribe form must carry.
+pub fn bot_check_fields_html(signer: &FormTimestampSigner) -> String {
+    let signed = signer.sign(Utc::now().timestamp());
+    format!(
+        r#"<input type="hidden" name="form_timestamp" value="{}">
+        <input type="hidden" name="form_signature" value="{}">
+        <div style="position: absolute; left: -10000px;" aria-hidden="true">
+            <label>Leave this field empty
+                <input type="text" name="website" tabindex="-1" autocomplete="off">
+            </label>
+        </div>"#,
+        signed.timestamp, signed.signature
+    )
+}
+
+/// A problem with one of the fields of the subscribe form.
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
 }
 
 impl TryFrom<FormData> for NewSubscriber {
-    type Error = String;
+    type Error = Vec<FieldError>;
 
+    /// Validate every field, reporting all the problems at once.
     fn try_from(value: FormData) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(value.name)?;
-        let email = SubscriberEmail::parse(value.email)?;
-        Ok(Self { email, name })
+        let name = SubscriberName::parse(value.name);
+        let email = SubscriberEmail::parse(value.email);
+        match (name, email) {
+            (Ok(name), Ok(email)) => Ok(Self { email, name }),
+            (name, email) => {
+                let mut errors = Vec::new();
+                if let Err(e) = name {
+                    errors.push(FieldError {
+                        field: "name",
+                        message: e.to_string(),
+                    });
+                }
+                if let Err(e) = email {
+                    errors.push(FieldError {
+                        field: "email",
+                        message: e.to_string(),
+                    });
+                }
+                Err(errors)
+            }
+        }
+    }
+}
+
+/// A rejected submission, with what is needed to tell the client why.
+pub struct InvalidForm {
+    form: FormData,
+    errors: Vec<FieldError>,
+    bot_check_fields: String,
+    /// Browsers get the form back with inline messages, API clients get JSON.
+    respond_with_html: bool,
+}
+
+impl InvalidForm {
+    fn error_response(&self) -> HttpResponse {
+        if self.respond_with_html {
+            HttpResponse::BadRequest()
+                .content_type(ContentType::html())
+                .body(subscribe_form_html(
+                    &self.form,
+                    &self.errors,
+                    &self.bot_check_fields,
+                ))
+        } else {
+            HttpResponse::BadRequest().json(serde_json::json!({ "errors": self.errors }))
+        }
+    }
+}
+
+pub enum SubscribeError {
+    Validation(InvalidForm),
+    /// A query failed; the message says which step of the flow it was.
+    Database(&'static str, sqlx::Error),
+}
+
+impl std::fmt::Display for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubscribeError::Validation(invalid_form) => {
+                let messages: Vec<_> = invalid_form
+                    .errors
+                    .iter()
+                    .map(|e| e.message.as_str())
+                    .collect();
+                write!(f, "{}", messages.join("; "))
+            }
+            SubscribeError::Database(context, _) => write!(f, "{}", context),
+        }
+    }
+}
+
+impl std::fmt::Debug for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
     }
 }
 
-fn generate_subscription_token() -> String {
-    let rng = rand::thread_rng();
+impl std::error::Error for SubscribeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SubscribeError::Validation(_) => None,
+            SubscribeError::Database(_, e) => Some(e),
+        }
+    }
+}
+
+impl ResponseError for SubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscribeError::Validation(_) => StatusCode::BAD_REQUEST,
+            SubscribeError::Database(..) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 
-    rng.sample_iter(&Alphanumeric)
-        .take(25)
-        .map(char::from)
-        .collect()
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            SubscribeError::Validation(invalid_form) => invalid_form.error_response(),
+            // The cause is logged, not shown to the client.
+            SubscribeError::Database(..) => HttpResponse::new(self.status_code()),
+        }
+    }
 }
 
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, connection_pool, email_client, base_url),
+    skip(form, connection_pool, form_signer, request_id, request),
     fields(
-        subscriber_email = %form.email,
-        subscriber_name = %form.name)
+        subscriber_email = %Pii(&form.email),
+        subscriber_name = %Pii(&form.name))
 )]
 pub async fn subscribe(
     form: web::Form<FormData>,
     connection_pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
-    base_url: web::Data<ApplicationBaseUrl>,
-) -> HttpResponse {
-    let new_subscriber = match form.0.try_into() {
-        Ok(subscriber) => subscriber,
-        Err(_) => {
-            return HttpResponse::BadRequest().finish();
+    form_signer: web::Data<FormTimestampSigner>,
+    request_id: web::ReqData<RequestId>,
+    request: HttpRequest,
+) -> Result<HttpResponse, SubscribeError> {
+    match form.check_not_a_bot(&form_signer, Utc::now().timestamp()) {
+        Ok(()) => {}
+        // API clients never rendered the form: tell them what is missing
+        // rather than pretend to accept their submission.
+        Err(e @ BotCheckFailure::MissingTimestamp) => {
+            return Err(SubscribeError::Validation(InvalidForm {
+                form: form.0,
+                errors: vec![FieldError {
+                    field: "form_timestamp",
+                    message: e.to_string(),
+                }],
+                bot_check_fields: bot_check_fields_html(&form_signer),
+                respond_with_html: prefers_html(&request),
+            }));
         }
-    };
-    let mut transaction = match connection_pool.begin().await {
-        Ok(transaction) => transaction,
-        Err(_) => {
-            return HttpResponse::InternalServerError().finish();
+        Err(e) => {
+            // Look the same as a successful signup, so bots do not adapt.
+            tracing::warn!(reason = %e, "Dropped a subscription that looks automated");
+            return Ok(HttpResponse::Ok().finish());
         }
-    };
+    }
+    let new_subscriber = NewSubscriber::try_from(form.0.clone()).map_err(|errors| {
+        SubscribeError::Validation(InvalidForm {
+            form: form.0,
+            errors,
+            bot_check_fields: bot_check_fields_html(&form_signer),
+            respond_with_html: prefers_html(&request),
+        })
+    })?;
+    let mut transaction = connection_pool.begin().await.map_err(|e| {
+        SubscribeError::Database("Failed to acquire a Postgres connection from the pool.", e)
+    })?;
 
-    let subscriber_id = match insert_subscriber(&mut transaction, &new_subscriber).await {
-        Ok(id) => id,
-        Err(_) => {
-            return HttpResponse::InternalServerError().finish();
+    // New, pending and confirmed addresses all get the same 200 and queue
+    // one email, so that neither the response nor its timing reveals who is
+    // already on the list.
+    let existing_subscriber = get_subscriber_by_email(&mut transaction, &new_subscriber.email)
+        .await
+        .map_err(|e| SubscribeError::Database("Failed to look up an existing subscriber.", e))?;
+    let (subscriber_id, email) = match existing_subscriber {
+        Some(subscriber) if subscriber.status == "confirmed" => {
+            (subscriber.id, SubscriptionEmail::AlreadySubscribed)
+        }
+        // They never clicked the first link (or it expired), or they left
+        // and want to come back: send a new one.
+        Some(subscriber) => {
+            back_to_pending(&mut transaction, &subscriber).await?;
+            confirmation(&mut transaction, subscriber.id).await?
+        }
+        None => {
+            let inserted = insert_subscriber(&mut transaction, &new_subscriber)
+                .await
+                .map_err(|e| SubscribeError::Database("Failed to insert a new subscriber.", e))?;
+            let subscriber_id = match inserted {
+                Some(subscriber_id) => subscriber_id,
+                // A concurrent signup for the same address committed first:
+                // send this one a new link for that row.
+                None => {
+                    let subscriber =
+                        get_subscriber_by_email(&mut transaction, &new_subscriber.email)
+                            .await
+                            .and_then(|subscriber| subscriber.ok_or(sqlx::Error::RowNotFound))
+                            .map_err(|e| {
+                                SubscribeError::Database(
+                                    "Failed to look up a concurrently added subscriber.",
+                                    e,
+                                )
+                            })?;
+                    back_to_pending(&mut transaction, &subscriber).await?;
+                    subscriber.id
+                }
+            };
+            confirmation(&mut transaction, subscriber_id).await?
         }
     };
+    enqueue_subscription_email(&mut transaction, subscriber_id, email, &request_id)
+        .await
+        .map_err(|e| SubscribeError::Database("Failed to queue a subscription email.", e))?;
+
+    transaction.commit().await.map_err(|e| {
+        SubscribeError::Database("Failed to commit the transaction to store a subscriber.", e)
+    })?;
+
+    Ok(HttpResponse::Ok().finish())
+}
 
-    let subscription_token = match store_token(&mut transaction, subscriber_id).await {
-        Ok(token) => token,
-        Err(_) => {
-            return HttpResponse::InternalServerError().finish();
+/// A new confirmation link for `subscriber_id`.
+async fn confirmation(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(Uuid, SubscriptionEmail), SubscribeError> {
+    let subscription_token = store_token(transaction, subscriber_id).await.map_err(|e| {
+        SubscribeError::Database(
+            "Failed to store the confirmation token for a subscriber.",
+            e,
+        )
+    })?;
+    Ok((
+        subscriber_id,
+        SubscriptionEmail::Confirmation { subscription_token },
+    ))
+}
+
+/// The subscribe form, filled in with what was submitted and the reason
+/// each field was rejected.
+fn subscribe_form_html(form: &FormData, errors: &[FieldError], bot_check_fields: &str) -> String {
+    let error_html = |field: &str| {
+        let mut html = String::new();
+        for e in errors.iter().filter(|e| e.field == field) {
+            writeln!(html, "<p><i>{}</i></p>", escape_html(&e.message)).unwrap();
         }
+        html
     };
+    let name = escape_html(&form.name);
+    let email = escape_html(&form.email);
+    let name_errors = error_html("name");
+    let email_errors = error_html("email");
 
-    if transaction.commit().await.is_err() {
-        return HttpResponse::InternalServerError().finish();
-    }
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
 
-    if send_confirmation_email(
-        &email_client,
-        new_subscriber,
-        &base_url.0,
-        &subscription_token,
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Subscribe</title>
+</head>
+
+<body>
+    <form action="/subscriptions" method="post">
+        <label>Name
+            <input type="text" placeholder="Enter your name" name="name" value="{name}">
+        </label>
+        {name_errors}
+        <label>Email
+            <input type="text" placeholder="Enter your email" name="email" value="{email}">
+        </label>
+        {email_errors}
+        {bot_check_fields}
+        <button type="submit">Subscribe</button>
+    </form>
+</body>
+
+</html>"#,
     )
-    .await
-    .is_err()
-    {
-        return HttpResponse::InternalServerError().finish();
-    }
+}
 
-    HttpResponse::Ok().finish()
+pub struct ExistingSubscriber {
+    id: Uuid,
+    status: String,
 }
 
 #[tracing::instrument(
-    name = "Sending confirmation email",
-    skip(email_client, new_subscriber, base_url, subscription_token)
+    name = "Looking for an existing subscriber with the same email",
+    skip(transaction, email)
 )]
-pub async fn send_confirmation_email(
-    email_client: &EmailClient,
-    new_subscriber: NewSubscriber,
-    base_url: &str,
-    subscription_token: &str,
-) -> Result<(), EmailClientError> {
-    let confirmation_link = format!(
-        "{}/subscriptions/confirm?subscription_token={}",
-        base_url, subscription_token
-    );
-
-    let plain_body = format!(
-        "Welcome to Roy's newsletter!\nVisit {} to confirm your subscription",
-        confirmation_link
-    );
-    let html_body = format!(
-        "Welcome to Roy's newsletter!<br />\
-        Click <a href=\"{}\">here</a> to confirm your subscription",
-        confirmation_link
-    );
+pub async fn get_subscriber_by_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Option<ExistingSubscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        ExistingSubscriber,
+        r#"SELECT id, status FROM subscriptions
+        WHERE lower(email) = lower($1)
+        FOR UPDATE"#,
+        email.as_ref()
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+}
 
-    email_client
-        .send_email(&new_subscriber.email, "Welcome!", &html_body, &plain_body)
+/// Revoke the links already sent to `subscriber`, who is to get a new one.
+async fn back_to_pending(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber: &ExistingSubscriber,
+) -> Result<(), SubscribeError> {
+    revoke_tokens(transaction, subscriber.id)
+        .await
+        .map_err(|e| {
+            SubscribeError::Database("Failed to revoke previous subscription tokens.", e)
+        })?;
+    mark_as_pending(transaction, subscriber.id)
         .await
+        .map_err(|e| SubscribeError::Database("Failed to mark a subscriber as pending.", e))
+}
+
+#[tracing::instrument(
+    name = "Marking subscriber as pending confirmation",
+    skip(transaction, subscriber_id)
+)]
+pub async fn mark_as_pending(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'pending_confirmation' WHERE id = $1"#,
+        subscriber_id,
+    );
+    transaction.execute(query).await?;
+    Ok(())
 }
 
 #[tracing::instrument(
     name = "Inserting new subscriber into db"
-    skip(transaction,new_subscriber)
+    skip(transaction, new_subscriber)
 )]
+/// Returns `None`, rather than failing, if the email was added since it was
+/// looked up.
 pub async fn insert_subscriber(
     transaction: &mut Transaction<'_, Postgres>,
     new_subscriber: &NewSubscriber,
-) -> Result<Uuid, sqlx::Error> {
-    let subscriber_id = Uuid::new_v4();
-    let query = sqlx::query!(
-        r#"INSERT INTO subscriptions (id, email, name, subscribed_at, status) 
-        VALUES ($1, $2, $3, $4, 'pending_confirmation')"#,
-        subscriber_id,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"INSERT INTO subscriptions (id, email, name, subscribed_at, status, unsubscribe_token)
+        VALUES ($1, $2, $3, $4, 'pending_confirmation', $5)
+        ON CONFLICT (lower(email)) DO NOTHING
+        RETURNING id"#,
+        Uuid::new_v4(),
         new_subscriber.email.as_ref(),
         new_subscriber.name.as_ref(),
-        Utc::now()
-    );
-    transaction.execute(query).await.map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
-
-    Ok(subscriber_id)
+        Utc::now(),
+        generate_token()
+    )
+    .fetch_optional(&mut **transaction)
+    .await
 }
 #[tracing::instrument(
     name = "Storing subscription token in db",
@@ -156,17 +425,32 @@ pub async fn store_token(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber_id: Uuid,
 ) -> Result<String, sqlx::Error> {
-    let subscription_token = generate_subscription_token();
+    let subscription_token = generate_token();
     let query = sqlx::query!(
-        r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id) 
-        VALUES ($1, $2)"#,
+        r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id, created_at)
+        VALUES ($1, $2, $3)"#,
         subscription_token,
         subscriber_id,
+        Utc::now()
     );
-    transaction.execute(query).await.map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
+    transaction.execute(query).await?;
 
     Ok(subscription_token)
 }
+
+/// Invalidate every confirmation link previously sent to a subscriber.
+#[tracing::instrument(
+    name = "Revoking previous subscription tokens",
+    skip(transaction, subscriber_id)
+)]
+pub async fn revoke_tokens(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1"#,
+        subscriber_id,
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
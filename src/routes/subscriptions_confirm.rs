@@ -1,7 +1,10 @@
+use crate::{startup::SubscriptionTokenTtl, utils::error_chain_fmt};
 use actix_web::{
+    http::{header::ContentType, StatusCode},
     web::{Data, Query},
-    HttpResponse,
+    HttpResponse, ResponseError,
 };
+use chrono::{DateTime, Utc};
 use sqlx::PgPool;
 use uuid::Uuid;
 
@@ -10,64 +13,159 @@ pub struct Parameters {
     subscription_token: String,
 }
 
+struct StoredToken {
+    subscriber_id: Uuid,
+    created_at: DateTime<Utc>,
+}
+
+pub enum ConfirmError {
+    UnknownToken,
+    ExpiredToken,
+    /// A query failed; the message says which step of the flow it was.
+    Database(&'static str, sqlx::Error),
+}
+
+impl std::fmt::Display for ConfirmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfirmError::UnknownToken => {
+                write!(
+                    f,
+                    "There is no subscriber associated with the provided token."
+                )
+            }
+            ConfirmError::ExpiredToken => write!(f, "The subscription token has expired."),
+            ConfirmError::Database(context, _) => write!(f, "{}", context),
+        }
+    }
+}
+
+impl std::fmt::Debug for ConfirmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl std::error::Error for ConfirmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfirmError::UnknownToken | ConfirmError::ExpiredToken => None,
+            ConfirmError::Database(_, e) => Some(e),
+        }
+    }
+}
+
+impl ResponseError for ConfirmError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConfirmError::UnknownToken => StatusCode::UNAUTHORIZED,
+            ConfirmError::ExpiredToken => StatusCode::GONE,
+            ConfirmError::Database(..) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ConfirmError::ExpiredToken => expired_link(),
+            ConfirmError::UnknownToken | ConfirmError::Database(..) => {
+                HttpResponse::new(self.status_code())
+            }
+        }
+    }
+}
+
 #[tracing::instrument(
     name = "Confirm a pending subscriber",
-    skip(parameters, connection_pool)
+    skip(parameters, connection_pool, token_ttl)
 )]
-pub async fn confirm(parameters: Query<Parameters>, connection_pool: Data<PgPool>) -> HttpResponse {
-    let subscriber_id =
-        match get_subscriber_id_by_token(&parameters.subscription_token, &connection_pool).await {
-            Ok(id) => id,
-            Err(_) => return HttpResponse::BadRequest().finish(),
-        };
-    match subscriber_id {
-        None => return HttpResponse::Unauthorized().finish(),
-        Some(subscriber_id) => {
-            if set_subscriber_status(subscriber_id, &connection_pool, "confirmed")
-                .await
-                .is_err()
-            {
-                return HttpResponse::InternalServerError().finish();
-            }
-        }
-    };
+pub async fn confirm(
+    parameters: Query<Parameters>,
+    connection_pool: Data<PgPool>,
+    token_ttl: Data<SubscriptionTokenTtl>,
+) -> Result<HttpResponse, ConfirmError> {
+    let token = get_token(&parameters.subscription_token, &connection_pool)
+        .await
+        .map_err(|e| ConfirmError::Database("Failed to retrieve the subscription token.", e))?
+        .ok_or(ConfirmError::UnknownToken)?;
+    if is_expired(token.created_at, &token_ttl) {
+        return Err(ConfirmError::ExpiredToken);
+    }
+    let confirmed = confirm_subscriber(token.subscriber_id, &connection_pool)
+        .await
+        .map_err(|e| ConfirmError::Database("Failed to mark the subscriber as confirmed.", e))?;
+    if !confirmed {
+        return Err(ConfirmError::UnknownToken);
+    }
 
-    HttpResponse::Ok().finish()
+    Ok(HttpResponse::Ok().finish())
 }
 
-async fn get_subscriber_id_by_token(
+fn is_expired(created_at: DateTime<Utc>, token_ttl: &SubscriptionTokenTtl) -> bool {
+    // A TTL too large for chrono never runs out.
+    chrono::Duration::from_std(token_ttl.0)
+        .ok()
+        .and_then(|ttl| created_at.checked_add_signed(ttl))
+        .is_some_and(|expires_at| expires_at < Utc::now())
+}
+
+fn expired_link() -> HttpResponse {
+    HttpResponse::Gone().content_type(ContentType::html()).body(
+        r#"<!DOCTYPE html>
+<html lang="en">
+
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Confirmation link expired</title>
+</head>
+
+<body>
+    <p>This confirmation link has expired.</p>
+    <p><a href="/">Subscribe again</a> with the same email to receive a new one.</p>
+</body>
+
+</html>"#,
+    )
+}
+
+async fn get_token(
     token: &str,
     connection_pool: &PgPool,
-) -> Result<Option<Uuid>, sqlx::Error> {
-    let result = sqlx::query!(
-        r#" SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1 "#,
+) -> Result<Option<StoredToken>, sqlx::Error> {
+    sqlx::query_as!(
+        StoredToken,
+        r#"SELECT subscriber_id, created_at
+        FROM subscription_tokens
+        WHERE subscription_token = $1"#,
         token
     )
     .fetch_optional(connection_pool)
     .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
-
-    Ok(result.map(|r| r.subscriber_id))
 }
 
-async fn set_subscriber_status(
+/// Confirm a pending subscriber and consume their confirmation links.
+///
+/// Returns `false` if they were not pending: a link must not bring back
+/// someone who has left the list.
+async fn confirm_subscriber(
     subscriber_id: Uuid,
     connection_pool: &PgPool,
-    status: &str,
-) -> Result<(), sqlx::Error> {
+) -> Result<bool, sqlx::Error> {
+    let mut transaction = connection_pool.begin().await?;
+    let confirmed = sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'confirmed'
+        WHERE id = $1 AND status = 'pending_confirmation'"#,
+        subscriber_id
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected()
+        == 1;
     sqlx::query!(
-        r#"UPDATE subscriptions SET status = $1 WHERE id = $2"#,
-        status,
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1"#,
         subscriber_id
     )
-    .execute(connection_pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
-    Ok(())
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(confirmed)
 }
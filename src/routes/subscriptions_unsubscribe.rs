@@ -0,0 +1,148 @@
+use actix_web::{
+    http::header::ContentType,
+    web::{Data, Query},
+    HttpResponse,
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct UnsubscribeParameters {
+    unsubscribe_token: String,
+}
+
+/// The link that lets a subscriber leave the list.
+///
+/// Following it from the email body (`GET`) only asks for confirmation:
+/// link scanners and prefetchers follow every link in an email. The
+/// subscriber is removed by the `POST` that confirms, which is also the
+/// RFC 8058 one-click target of `List-Unsubscribe`.
+pub fn unsubscribe_link(base_url: &str, unsubscribe_token: &str) -> String {
+    format!(
+        "{}/subscriptions/unsubscribe?unsubscribe_token={}",
+        base_url, unsubscribe_token
+    )
+}
+
+#[tracing::instrument(
+    name = "Ask for confirmation before unsubscribing",
+    skip(parameters, connection_pool)
+)]
+pub async fn unsubscribe_form(
+    parameters: Query<UnsubscribeParameters>,
+    connection_pool: Data<PgPool>,
+) -> HttpResponse {
+    match token_exists(&parameters.unsubscribe_token, &connection_pool).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::Unauthorized().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+    // The token is known, so it is made of the characters we generate
+    // tokens from: it can go in the form's action as it is.
+    let action = unsubscribe_link("", &parameters.unsubscribe_token);
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribe</title>
+</head>
+
+<body>
+    <p>Do you want to stop receiving our newsletter?</p>
+    <form action="{action}" method="post">
+        <input hidden type="text" name="List-Unsubscribe" value="One-Click">
+        <button type="submit">Unsubscribe</button>
+    </form>
+</body>
+
+</html>"#,
+        ))
+}
+
+#[tracing::instrument(
+    name = "Unsubscribe a subscriber",
+    skip(parameters, connection_pool),
+    fields(subscriber_id = tracing::field::Empty)
+)]
+pub async fn unsubscribe(
+    parameters: Query<UnsubscribeParameters>,
+    connection_pool: Data<PgPool>,
+) -> HttpResponse {
+    let subscriber_id =
+        match unsubscribe_by_token(&parameters.unsubscribe_token, &connection_pool).await {
+            Ok(id) => id,
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
+    match subscriber_id {
+        None => HttpResponse::Unauthorized().finish(),
+        Some(subscriber_id) => {
+            tracing::Span::current()
+                .record("subscriber_id", tracing::field::display(&subscriber_id));
+            HttpResponse::Ok().content_type(ContentType::html()).body(
+                r#"<!DOCTYPE html>
+<html lang="en">
+
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribed</title>
+</head>
+
+<body>
+    <p>You have been unsubscribed. You will not receive any more emails from us.</p>
+</body>
+
+</html>"#,
+            )
+        }
+    }
+}
+
+/// Mark the owner of `token` as unsubscribed, returning their id.
+///
+/// Their confirmation links are revoked, so that an old one cannot bring
+/// them back. Unsubscribing twice is not an error: the second time is a
+/// no-op.
+async fn unsubscribe_by_token(
+    token: &str,
+    connection_pool: &PgPool,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"WITH unsubscribed AS (
+            UPDATE subscriptions SET status = 'unsubscribed'
+            WHERE unsubscribe_token = $1
+            RETURNING id
+        ), revoked AS (
+            DELETE FROM subscription_tokens
+            WHERE subscriber_id IN (SELECT id FROM unsubscribed)
+        )
+        SELECT id AS "id!" FROM unsubscribed"#,
+        token
+    )
+    .fetch_optional(connection_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(result.map(|r| r.id))
+}
+
+async fn token_exists(token: &str, connection_pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE unsubscribe_token = $1"#,
+        token
+    )
+    .fetch_optional(connection_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(result.is_some())
+}
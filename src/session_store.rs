@@ -0,0 +1,149 @@
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::cookie::time::Duration;
+use chrono::Utc;
+use rand::distributions::{Alphanumeric, DistString};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+type SessionState = HashMap<String, String>;
+
+/// Server-side session storage backed by the `sessions` table.
+///
+/// The browser only ever sees the opaque session key; the state itself
+/// lives in Postgres next to the rest of the application data.
+#[derive(Clone)]
+pub struct PgSessionStore {
+    connection_pool: PgPool,
+}
+
+impl PgSessionStore {
+    pub fn new(connection_pool: PgPool) -> Self {
+        Self { connection_pool }
+    }
+}
+
+/// Log `user_id` out of every session they have open.
+#[tracing::instrument(name = "Deleting user sessions", skip(transaction))]
+pub async fn delete_user_sessions(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    // Session values are stored JSON-encoded, see `TypedSession`.
+    let user_id = serde_json::to_string(&user_id).expect("A UUID can always be encoded as JSON");
+    sqlx::query!(
+        r#"DELETE FROM sessions WHERE state->>'user_id' = $1"#,
+        user_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+fn generate_session_key() -> SessionKey {
+    Alphanumeric
+        .sample_string(&mut rand::thread_rng(), 64)
+        .try_into()
+        .expect("A 64 characters long string is a valid session key")
+}
+
+fn expires_at(ttl: &Duration) -> chrono::DateTime<Utc> {
+    Utc::now() + chrono::Duration::seconds(ttl.whole_seconds())
+}
+
+impl SessionStore for PgSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let row = sqlx::query!(
+            r#"SELECT state FROM sessions WHERE session_key = $1 AND expires_at > now()"#,
+            session_key.as_ref()
+        )
+        .fetch_optional(&self.connection_pool)
+        .await
+        .map_err(|e| LoadError::Other(e.into()))?;
+
+        row.map(|r| serde_json::from_value(r.state))
+            .transpose()
+            .map_err(|e| LoadError::Deserialization(e.into()))
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let state =
+            serde_json::to_value(session_state).map_err(|e| SaveError::Serialization(e.into()))?;
+        let session_key = generate_session_key();
+
+        // Piggyback on writes to get rid of sessions that have expired,
+        // so that the table does not grow without bound.
+        sqlx::query!(r#"DELETE FROM sessions WHERE expires_at < now()"#)
+            .execute(&self.connection_pool)
+            .await
+            .map_err(|e| SaveError::Other(e.into()))?;
+
+        sqlx::query!(
+            r#"INSERT INTO sessions (session_key, state, expires_at) VALUES ($1, $2, $3)"#,
+            session_key.as_ref(),
+            state,
+            expires_at(ttl)
+        )
+        .execute(&self.connection_pool)
+        .await
+        .map_err(|e| SaveError::Other(e.into()))?;
+
+        Ok(session_key)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let state = serde_json::to_value(&session_state)
+            .map_err(|e| UpdateError::Serialization(e.into()))?;
+
+        let result = sqlx::query!(
+            r#"UPDATE sessions SET state = $1, expires_at = $2 WHERE session_key = $3"#,
+            state,
+            expires_at(ttl),
+            session_key.as_ref()
+        )
+        .execute(&self.connection_pool)
+        .await
+        .map_err(|e| UpdateError::Other(e.into()))?;
+
+        if result.rows_affected() == 0 {
+            // The session expired and was cleaned up in the meantime:
+            // store the state under a brand-new key.
+            return self.save(session_state, ttl).await.map_err(|e| match e {
+                SaveError::Serialization(e) => UpdateError::Serialization(e),
+                SaveError::Other(e) => UpdateError::Other(e),
+            });
+        }
+
+        Ok(session_key)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"UPDATE sessions SET expires_at = $1 WHERE session_key = $2"#,
+            expires_at(ttl),
+            session_key.as_ref()
+        )
+        .execute(&self.connection_pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"DELETE FROM sessions WHERE session_key = $1"#,
+            session_key.as_ref()
+        )
+        .execute(&self.connection_pool)
+        .await?;
+        Ok(())
+    }
+}
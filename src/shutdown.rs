@@ -0,0 +1,154 @@
+use crate::utils::e500;
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::Notify;
+
+/// The number of requests the HTTP server is currently handling.
+#[derive(Clone, Default)]
+pub struct InFlightRequests(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+struct InFlightGuard(InFlightRequests);
+
+impl InFlightRequests {
+    fn start(&self) -> InFlightGuard {
+        self.0.count.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.clone())
+    }
+
+    /// Count `f` as in flight until it completes, like the request that
+    /// hands it off to another task: shutdown waits for both alike.
+    pub fn track<F: Future>(&self, f: F) -> impl Future<Output = F::Output> {
+        let guard = self.start();
+        async move {
+            let _guard = guard;
+            f.await
+        }
+    }
+
+    /// Resolve once no request is being handled.
+    pub async fn wait_until_idle(&self) {
+        loop {
+            // Created before checking, so that a request finishing in
+            // between still wakes us up.
+            let idle = self.0.idle.notified();
+            if self.0.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.0 .0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0 .0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Count the request as in flight until its response body has been sent.
+pub async fn track_in_flight_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let in_flight = req
+        .app_data::<web::Data<InFlightRequests>>()
+        .cloned()
+        .ok_or_else(|| e500("In-flight request tracking is not configured"))?;
+    let guard = in_flight.start();
+    let response = next.call(req).await?;
+    Ok(response.map_body(|_, body| TrackedBody {
+        body: body.boxed(),
+        _guard: guard,
+    }))
+}
+
+/// A response body that keeps its request in flight until it is dropped.
+struct TrackedBody {
+    body: BoxBody,
+    _guard: InFlightGuard,
+}
+
+impl MessageBody for TrackedBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<actix_web::web::Bytes, Self::Error>>> {
+        Pin::new(&mut self.body).poll_next(cx)
+    }
+}
+
+/// Resolve on SIGINT or, on Unix, SIGTERM.
+pub async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for SIGINT");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InFlightRequests;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn wait_until_idle_resolves_once_the_last_request_is_done() {
+        let in_flight = InFlightRequests::default();
+        let first = in_flight.start();
+        let second = in_flight.start();
+
+        let waiting = tokio::spawn({
+            let in_flight = in_flight.clone();
+            async move { in_flight.wait_until_idle().await }
+        });
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiting.is_finished());
+
+        drop(second);
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .expect("Still waiting with no request in flight.")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn wait_until_idle_resolves_immediately_when_idle() {
+        InFlightRequests::default().wait_until_idle().await;
+    }
+}
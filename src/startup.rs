@@ -1,41 +1,68 @@
-use crate::configuration::DatabaseSettings;
+use crate::authentication::{create_admin, reject_anonymous_users};
 use crate::configuration::Settings;
 use crate::email_client::EmailClient;
+use crate::issue_delivery_worker;
+use crate::metrics::{self, record_http_metrics, MetricsToken};
+use crate::rate_limit::rate_limit_subscriptions;
+use crate::request_id::{assign_request_id, RequestIdRootSpanBuilder};
+use crate::routes::admin;
 use crate::routes::health_check;
 use crate::routes::homepage;
+use crate::routes::login;
+use crate::routes::newsletters;
+use crate::routes::password_reset;
 use crate::routes::subscriptions;
 use crate::routes::subscriptions_confirm;
+use crate::routes::subscriptions_unsubscribe;
+use crate::session_store::PgSessionStore;
+use crate::shutdown::{shutdown_signal, track_in_flight_requests, InFlightRequests};
+use crate::subscription_email_worker;
+use crate::telemetry::{scope_pii_redaction, PiiRedaction};
+use actix_session::SessionMiddleware;
+use actix_web::cookie::Key;
 use actix_web::dev::Server;
+use actix_web::middleware::from_fn;
 use actix_web::{
+    guard,
     web::{self, Data},
     App, HttpServer,
 };
+use actix_web_flash_messages::storage::CookieMessageStore;
+use actix_web_flash_messages::FlashMessagesFramework;
+use secrecy::ExposeSecret;
 use sqlx::postgres::PgPoolOptions;
-use sqlx::PgPool;
+use sqlx::{ConnectOptions, PgPool};
+use std::future::Future;
 use std::net::TcpListener;
+use std::time::Duration;
+use tokio::task::JoinError;
+use tokio_util::sync::CancellationToken;
 use tracing_actix_web::TracingLogger;
 
 pub struct Application {
     pub port: u16,
     pub server: Server,
+    connection_pool: PgPool,
+    email_client: EmailClient,
+    subscription_email_client: EmailClient,
+    base_url: String,
+    send_already_subscribed_email: bool,
+    in_flight_requests: InFlightRequests,
+    shutdown: CancellationToken,
+    shutdown_grace_period: Duration,
+    pii_redaction: PiiRedaction,
 }
 
 impl Application {
     pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
-        let connection_pool = get_connection_pool(&configuration.database);
-
-        let sender_email = configuration
-            .email_client
-            .sender()
-            .expect("Invalid sender email address.");
-
-        let timeout = configuration.email_client.timeout();
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
-            configuration.email_client.authorization_token,
-            timeout,
-        );
+        let connection_pool = get_connection_pool(&configuration);
+        if let Some(admin) = &configuration.admin {
+            create_admin(admin, &connection_pool)
+                .await
+                .map_err(std::io::Error::other)?;
+        }
+
+        let email_client = configuration.email_client.clone().client();
 
         let address = format!(
             "{}:{}",
@@ -43,25 +70,158 @@ impl Application {
         );
         let listener = TcpListener::bind(address)?;
         let port = listener.local_addr().unwrap().port();
+        let in_flight_requests = InFlightRequests::default();
         let server = run(
             listener,
-            connection_pool,
+            connection_pool.clone(),
             email_client,
-            configuration.application.base_url,
+            in_flight_requests.clone(),
+            configuration.clone(),
         )?;
 
-        Ok(Self { port, server })
+        // Each worker gets its own client, the HTTP server owns the first one.
+        let worker_email_client = configuration.email_client.clone().client();
+        let subscription_email_client = configuration.email_client.clone().client();
+
+        Ok(Self {
+            port,
+            server,
+            connection_pool,
+            email_client: worker_email_client,
+            subscription_email_client,
+            in_flight_requests,
+            shutdown_grace_period: configuration.application.shutdown_grace_period(),
+            pii_redaction: configuration.telemetry.pii_redaction(),
+            base_url: configuration.application.base_url,
+            send_already_subscribed_email: configuration.application.send_already_subscribed_email,
+            shutdown: CancellationToken::new(),
+        })
     }
     pub fn port(&self) -> u16 {
         self.port
     }
+
+    /// Cancelling it shuts the application down, as SIGTERM or SIGINT do.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Run the HTTP server and the background email workers side by side.
+    ///
+    /// On SIGTERM/SIGINT, or as soon as either of them exits, the server
+    /// stops accepting connections and both get the grace period to finish
+    /// what they are doing. The connection pool is closed last.
+    ///
+    /// Draining is done here rather than by actix's graceful stop, which can
+    /// drop a response that completes while its workers are stopping: the
+    /// server is only stopped once no request is in flight.
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+        let shutdown = self.shutdown;
+        let server_handle = self.server.handle();
+        let server = tokio::spawn(cancel_on_exit(self.server, shutdown.clone()));
+        let deliveries = issue_delivery_worker::run_worker_until_stopped(
+            self.connection_pool.clone(),
+            self.email_client,
+            self.base_url.clone(),
+            shutdown.clone(),
+        );
+        let subscription_emails = subscription_email_worker::run_worker_until_stopped(
+            self.connection_pool.clone(),
+            self.subscription_email_client,
+            self.base_url,
+            self.send_already_subscribed_email,
+            shutdown.clone(),
+        );
+        let workers = async move { tokio::try_join!(deliveries, subscription_emails).map(|_| ()) };
+        let mut worker = tokio::spawn(cancel_on_exit(
+            self.pii_redaction.scope(workers),
+            shutdown.clone(),
+        ));
+
+        tokio::select! {
+            _ = shutdown.cancelled() => {}
+            _ = shutdown_signal() => {
+                tracing::info!("Shutdown signal received, draining in-flight work");
+                shutdown.cancel();
+            }
+        }
+
+        let deadline = tokio::time::Instant::now() + self.shutdown_grace_period;
+        server_handle.pause().await;
+        if tokio::time::timeout_at(deadline, self.in_flight_requests.wait_until_idle())
+            .await
+            .is_err()
+        {
+            tracing::warn!("Requests still in flight at the end of the grace period");
+        }
+        // Responses may still be flushing: give connections a moment to
+        // finish before they are closed.
+        server_handle.stop(true).await;
+        report_exit("API", server.await);
+        match tokio::time::timeout_at(deadline, &mut worker).await {
+            Ok(outcome) => report_exit("Background worker", outcome),
+            Err(_) => {
+                tracing::warn!("Background worker did not stop within the grace period");
+                // Its transaction is rolled back: the task will be retried,
+                // and its email sent again if it was already out.
+                worker.abort();
+            }
+        }
+
+        // A connection used by an actix worker may never make it back to the
+        // pool once that worker has stopped: do not wait for it forever.
+        if tokio::time::timeout_at(deadline, self.connection_pool.close())
+            .await
+            .is_err()
+        {
+            tracing::warn!("Not every database connection was closed within the grace period");
+        }
+        tracing::info!("Shutdown complete");
+        Ok(())
     }
 }
 
-pub fn get_connection_pool(db_config: &DatabaseSettings) -> PgPool {
-    PgPoolOptions::new().connect_lazy_with(db_config.with_db())
+/// Shut everything else down when `task` exits, whatever the reason.
+async fn cancel_on_exit<T>(task: impl Future<Output = T>, shutdown: CancellationToken) -> T {
+    let outcome = task.await;
+    shutdown.cancel();
+    outcome
+}
+
+fn report_exit<E>(task_name: &str, outcome: Result<Result<(), E>, JoinError>)
+where
+    E: std::fmt::Debug + std::fmt::Display,
+{
+    match outcome {
+        Ok(Ok(())) => {
+            tracing::info!("{} has exited", task_name)
+        }
+        Ok(Err(e)) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "{} failed",
+                task_name
+            )
+        }
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "{} task failed to complete",
+                task_name
+            )
+        }
+    }
+}
+
+pub fn get_connection_pool(configuration: &Settings) -> PgPool {
+    let mut options = configuration.database.with_db();
+    // Statements may carry personal data.
+    if configuration.telemetry.redact_pii {
+        options = options.disable_statement_logging();
+    }
+    PgPoolOptions::new().connect_lazy_with(options)
 }
 
 // We need to define a wrapper type in order to retrieve the URL
@@ -71,29 +231,139 @@ pub fn get_connection_pool(db_config: &DatabaseSettings) -> PgPool {
 #[derive(Debug)]
 pub struct ApplicationBaseUrl(pub String);
 
+pub struct SubscriptionTokenTtl(pub Duration);
+
 pub fn run(
     listener: TcpListener,
     connection_pool: PgPool,
     email_client: EmailClient,
-    base_url: String,
+    in_flight_requests: InFlightRequests,
+    configuration: Settings,
 ) -> Result<Server, std::io::Error> {
+    let secret_key = Key::from(
+        configuration
+            .application
+            .hmac_secret
+            .expose_secret()
+            .as_bytes(),
+    );
+    let message_store = CookieMessageStore::builder(secret_key.clone()).build();
+    let message_framework = FlashMessagesFramework::builder(message_store).build();
+    let session_store = PgSessionStore::new(connection_pool.clone());
     let connection_pool = Data::new(connection_pool);
     let email_client = Data::new(email_client);
-    let base_url = Data::new(ApplicationBaseUrl(base_url));
+    let subscription_token_ttl = Data::new(SubscriptionTokenTtl(
+        configuration.application.subscription_token_ttl(),
+    ));
+    let base_url = Data::new(ApplicationBaseUrl(configuration.application.base_url));
+    // Shared by every worker thread, so that limits apply to the whole process.
+    let subscription_rate_limiter = Data::new(
+        configuration
+            .rate_limit
+            .clone()
+            .limiter()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+    );
+    // The same limits, counted separately from signups.
+    let password_reset_rate_limiter = Data::new(
+        configuration
+            .rate_limit
+            .limiter()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+    );
+    let form_signer = Data::new(configuration.subscribe_form.signer());
+    let metrics_token = Data::new(MetricsToken(configuration.metrics.bearer_token));
+    let email_provider_health = Data::new(health_check::EmailProviderHealth::new(
+        configuration.health.email_provider_cache_ttl(),
+    ));
+    let health_settings = Data::new(configuration.health);
+    let pii_redaction = Data::new(configuration.telemetry.pii_redaction());
+    let in_flight_requests = Data::new(in_flight_requests);
     let server = HttpServer::new(move || {
         App::new()
-            .wrap(TracingLogger::default())
-            .route("/health_check", web::get().to(health_check::health_check))
-            .route("/subscriptions", web::post().to(subscriptions::subscribe))
+            .wrap(from_fn(scope_pii_redaction))
+            .wrap(message_framework.clone())
+            .wrap(SessionMiddleware::new(
+                session_store.clone(),
+                secret_key.clone(),
+            ))
+            .wrap(from_fn(record_http_metrics))
+            .wrap(TracingLogger::<RequestIdRootSpanBuilder>::new())
+            .wrap(from_fn(assign_request_id))
+            .wrap(from_fn(track_in_flight_requests))
+            .route("/health/live", web::get().to(health_check::health_live))
+            .route("/health/ready", web::get().to(health_check::health_ready))
+            // Kept for the probes that were set up against the old page.
+            .route("/health_check", web::get().to(health_check::health_live))
+            .route("/metrics", web::get().to(metrics::metrics))
+            .route("/login", web::get().to(login::login_form))
+            .route("/login", web::post().to(login::login))
+            .route(
+                "/password/reset",
+                web::get().to(password_reset::password_reset_form),
+            )
+            .service(
+                web::resource("/password/reset")
+                    .guard(guard::Post())
+                    .wrap(from_fn(rate_limit_subscriptions))
+                    .app_data(password_reset_rate_limiter.clone())
+                    .route(web::post().to(password_reset::request_password_reset)),
+            )
+            .route(
+                "/password/reset/confirm",
+                web::get().to(password_reset::password_reset_confirm_form),
+            )
+            .route(
+                "/password/reset/confirm",
+                web::post().to(password_reset::confirm_password_reset),
+            )
+            .service(
+                web::scope("/admin")
+                    .wrap(from_fn(reject_anonymous_users))
+                    .route("/dashboard", web::get().to(admin::admin_dashboard))
+                    .route("/password", web::get().to(admin::change_password_form))
+                    .route("/password", web::post().to(admin::change_password))
+                    .route("/logout", web::post().to(admin::log_out)),
+            )
+            .service(
+                web::resource("/subscriptions")
+                    .wrap(from_fn(rate_limit_subscriptions))
+                    .route(web::post().to(subscriptions::subscribe)),
+            )
             .route(
                 "/subscriptions/confirm",
                 web::get().to(subscriptions_confirm::confirm),
             )
+            .route(
+                "/subscriptions/unsubscribe",
+                web::get().to(subscriptions_unsubscribe::unsubscribe_form),
+            )
+            .route(
+                "/subscriptions/unsubscribe",
+                web::post().to(subscriptions_unsubscribe::unsubscribe),
+            )
+            .route(
+                "/newsletters",
+                web::post().to(newsletters::publish_newsletter),
+            )
             .route("/", web::get().to(homepage::homepage))
             .app_data(connection_pool.clone())
             .app_data(email_client.clone())
             .app_data(base_url.clone())
+            .app_data(subscription_token_ttl.clone())
+            .app_data(subscription_rate_limiter.clone())
+            .app_data(form_signer.clone())
+            .app_data(metrics_token.clone())
+            .app_data(email_provider_health.clone())
+            .app_data(health_settings.clone())
+            .app_data(in_flight_requests.clone())
+            .app_data(pii_redaction.clone())
     })
+    // Signals are handled by `Application::run_until_stopped`, which also
+    // has to stop the background worker.
+    .disable_signals()
+    // Requests have already been drained by then, see `run_until_stopped`.
+    .shutdown_timeout(1)
     .listen(listener)?
     .run();
 
@@ -0,0 +1,334 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailClient, EmailClientError};
+use crate::issue_delivery_worker::ExecutionOutcome;
+use crate::request_id::RequestId;
+use crate::routes::unsubscribe_link;
+use crate::telemetry::Pii;
+use chrono::Utc;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+/// How many times an email is attempted before it is dropped.
+const MAX_ATTEMPTS: i16 = 6;
+
+/// The delay before the first new attempt, doubled after each failure.
+const FIRST_RETRY_DELAY_MINUTES: i64 = 1;
+
+/// What a signup is answered with.
+pub enum SubscriptionEmail {
+    Confirmation { subscription_token: String },
+    AlreadySubscribed,
+}
+
+impl SubscriptionEmail {
+    fn kind(&self) -> &'static str {
+        match self {
+            SubscriptionEmail::Confirmation { .. } => "confirmation",
+            SubscriptionEmail::AlreadySubscribed => "already_subscribed",
+        }
+    }
+
+    fn subscription_token(&self) -> Option<&str> {
+        match self {
+            SubscriptionEmail::Confirmation { subscription_token } => Some(subscription_token),
+            SubscriptionEmail::AlreadySubscribed => None,
+        }
+    }
+}
+
+/// Queue `email` for `subscriber_id`, to be sent by `run_worker_until_stopped`.
+///
+/// Signups only ever write to the database: whether an address is new,
+/// pending or already confirmed, the request does the same kind of work and
+/// takes about the same time.
+#[tracing::instrument(skip_all)]
+pub async fn enqueue_subscription_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    email: SubscriptionEmail,
+    request_id: &RequestId,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"INSERT INTO subscription_email_queue (
+            id, subscriber_id, kind, subscription_token, request_id
+        )
+        VALUES ($1, $2, $3, $4, $5)"#,
+        Uuid::new_v4(),
+        subscriber_id,
+        email.kind(),
+        email.subscription_token(),
+        request_id.as_str(),
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+/// Drain `subscription_email_queue` until `shutdown` is cancelled.
+///
+/// Already-subscribed emails are dropped rather than sent unless
+/// `send_already_subscribed` is on. As with `issue_delivery_worker`,
+/// cancellation is only checked between tasks and each task is claimed with
+/// `FOR UPDATE SKIP LOCKED`.
+pub async fn run_worker_until_stopped(
+    connection_pool: PgPool,
+    email_client: EmailClient,
+    base_url: String,
+    send_already_subscribed: bool,
+    shutdown: CancellationToken,
+) -> Result<(), anyhow::Error> {
+    while !shutdown.is_cancelled() {
+        let outcome = try_execute_task(
+            &connection_pool,
+            &email_client,
+            &base_url,
+            send_already_subscribed,
+        )
+        .await;
+        let idle = match outcome {
+            // Someone is waiting for their link: look more often than for
+            // newsletter deliveries.
+            Ok(ExecutionOutcome::EmptyQueue) => Duration::from_secs(1),
+            Err(_) => Duration::from_secs(1),
+            Ok(ExecutionOutcome::TaskCompleted) => continue,
+        };
+        tokio::select! {
+            _ = shutdown.cancelled() => {}
+            _ = tokio::time::sleep(idle) => {}
+        }
+    }
+    Ok(())
+}
+
+struct SubscriptionEmailTask {
+    id: Uuid,
+    kind: String,
+    subscription_token: Option<String>,
+    email: String,
+    unsubscribe_token: String,
+    /// How many attempts have already failed.
+    n_retries: i16,
+    /// The signup that queued it.
+    request_id: Option<RequestId>,
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(
+        subscriber_email = tracing::field::Empty,
+        kind = tracing::field::Empty,
+        x_request_id = tracing::field::Empty
+    ),
+    err
+)]
+pub async fn try_execute_task(
+    connection_pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+    send_already_subscribed: bool,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let Some((transaction, task)) = dequeue_task(connection_pool).await? else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    Span::current()
+        .record("subscriber_email", display(Pii(&task.email)))
+        .record("kind", display(&task.kind));
+
+    let request_id = task.request_id.clone();
+    let sending = send_task_email(
+        transaction,
+        email_client,
+        base_url,
+        send_already_subscribed,
+        task,
+    );
+    match request_id {
+        Some(request_id) => {
+            Span::current().record("x_request_id", display(&request_id));
+            request_id.scope(sending).await
+        }
+        None => sending.await,
+    }
+}
+
+async fn send_task_email(
+    transaction: PgTransaction,
+    email_client: &EmailClient,
+    base_url: &str,
+    send_already_subscribed: bool,
+    task: SubscriptionEmailTask,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let email = match SubscriberEmail::parse(task.email.clone()) {
+        Ok(email) => email,
+        Err(e) => {
+            tracing::warn!(error = %e, "Skipping an email to an invalid address");
+            delete_task(transaction, task.id).await?;
+            return Ok(ExecutionOutcome::TaskCompleted);
+        }
+    };
+    let outcome = match (task.kind.as_str(), &task.subscription_token) {
+        ("confirmation", Some(subscription_token)) => {
+            send_confirmation_email(
+                email_client,
+                &email,
+                base_url,
+                subscription_token,
+                &task.unsubscribe_token,
+            )
+            .await
+        }
+        ("already_subscribed", _) if send_already_subscribed => {
+            send_already_subscribed_email(email_client, &email, base_url, &task.unsubscribe_token)
+                .await
+        }
+        _ => Ok(()),
+    };
+
+    match outcome {
+        Ok(()) => delete_task(transaction, task.id).await?,
+        Err(e) if e.is_transient() && task.n_retries + 1 < MAX_ATTEMPTS => {
+            tracing::warn!(error = ?e, "Failed to send a subscription email. It will be retried.");
+            retry_later(transaction, task.id, task.n_retries).await?;
+        }
+        Err(e) => {
+            tracing::error!(error = ?e, "Giving up on sending a subscription email");
+            delete_task(transaction, task.id).await?;
+        }
+    }
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+#[tracing::instrument(skip_all)]
+async fn dequeue_task(
+    connection_pool: &PgPool,
+) -> Result<Option<(PgTransaction, SubscriptionEmailTask)>, anyhow::Error> {
+    let mut transaction = connection_pool.begin().await?;
+    let r = sqlx::query!(
+        r#"SELECT
+            q.id,
+            q.kind,
+            q.subscription_token,
+            q.n_retries,
+            q.request_id,
+            s.email,
+            s.unsubscribe_token
+        FROM subscription_email_queue q
+        JOIN subscriptions s ON s.id = q.subscriber_id
+        WHERE q.execute_after <= now()
+        FOR UPDATE OF q
+        SKIP LOCKED
+        LIMIT 1"#,
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let task = r.map(|r| SubscriptionEmailTask {
+        id: r.id,
+        kind: r.kind,
+        subscription_token: r.subscription_token,
+        email: r.email,
+        unsubscribe_token: r.unsubscribe_token,
+        n_retries: r.n_retries,
+        request_id: r.request_id.as_deref().and_then(RequestId::parse),
+    });
+    Ok(task.map(|task| (transaction, task)))
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_task(mut transaction: PgTransaction, id: Uuid) -> Result<(), anyhow::Error> {
+    let query = sqlx::query!(r#"DELETE FROM subscription_email_queue WHERE id = $1"#, id);
+    transaction.execute(query).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn retry_later(
+    mut transaction: PgTransaction,
+    id: Uuid,
+    n_retries: i16,
+) -> Result<(), anyhow::Error> {
+    let n_retries = n_retries + 1;
+    let delay = chrono::Duration::minutes(FIRST_RETRY_DELAY_MINUTES << (n_retries - 1));
+    let query = sqlx::query!(
+        r#"UPDATE subscription_email_queue
+        SET n_retries = $2, execute_after = $3
+        WHERE id = $1"#,
+        id,
+        n_retries,
+        Utc::now() + delay
+    );
+    transaction.execute(query).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Sending confirmation email",
+    skip(email_client, email, base_url, subscription_token, unsubscribe_token)
+)]
+pub async fn send_confirmation_email(
+    email_client: &EmailClient,
+    email: &SubscriberEmail,
+    base_url: &str,
+    subscription_token: &str,
+    unsubscribe_token: &str,
+) -> Result<(), EmailClientError> {
+    let confirmation_link = format!(
+        "{}/subscriptions/confirm?subscription_token={}",
+        base_url, subscription_token
+    );
+
+    let plain_body = format!(
+        "Welcome to Roy's newsletter!\nVisit {} to confirm your subscription",
+        confirmation_link
+    );
+    let html_body = format!(
+        "Welcome to Roy's newsletter!<br />\
+        Click <a href=\"{}\">here</a> to confirm your subscription",
+        confirmation_link
+    );
+
+    email_client
+        .send_email(
+            email,
+            "Welcome!",
+            &html_body,
+            &plain_body,
+            Some(&unsubscribe_link(base_url, unsubscribe_token)),
+        )
+        .await
+}
+
+/// Sent instead of a confirmation link when the address is already confirmed,
+/// if `send_already_subscribed_email` is on.
+#[tracing::instrument(
+    name = "Sending already subscribed email",
+    skip(email_client, email, base_url, unsubscribe_token)
+)]
+pub async fn send_already_subscribed_email(
+    email_client: &EmailClient,
+    email: &SubscriberEmail,
+    base_url: &str,
+    unsubscribe_token: &str,
+) -> Result<(), EmailClientError> {
+    let plain_body =
+        "Somebody, hopefully you, asked to subscribe this address to Roy's newsletter.\n\
+        You are already subscribed: there is nothing else to do.";
+    let html_body =
+        "Somebody, hopefully you, asked to subscribe this address to Roy's newsletter.<br />\
+        You are already subscribed: there is nothing else to do.";
+
+    email_client
+        .send_email(
+            email,
+            "You're already subscribed",
+            html_body,
+            plain_body,
+            Some(&unsubscribe_link(base_url, unsubscribe_token)),
+        )
+        .await
+}
@@ -1,12 +1,38 @@
+use crate::configuration::{LogFormat, LogSettings};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use hmac::{Hmac, Mac};
+use opentelemetry::propagation::Injector;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::RequestBuilder;
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use std::fmt::Display;
+use std::future::Future;
+use tokio::task::JoinHandle;
 use tracing::subscriber::set_global_default;
 use tracing::Subscriber;
+use tracing_appender::rolling::InitError;
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
-use tracing_subscriber::fmt::MakeWriter;
-use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriterExt};
+use tracing_subscriber::fmt::{self, MakeWriter};
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::{EnvFilter, Layer, Registry};
 
 /// Compose multiple layers into a `tracing`'s subscriber.
 ///
+/// Logs are written to `sink` in the configured format, and to a rolling
+/// file if there is one. Spans are also exported through `tracer_provider`,
+/// when there is one.
+///
 /// # Implementation Notes
 ///
 /// We are using `impl Subscriber` as return type to avoid having to
@@ -15,11 +41,14 @@ use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
 /// We need to explicitly call out that the returned subscriber is
 /// `Send` and `Sync` to make it possible to pass it to `init_subscriber`
 /// later on.
+/// The layers are boxed, since which ones are used is only known at
+/// runtime.
 pub fn get_subscriber<Sink>(
     name: String,
-    env_filter: String,
+    settings: &LogSettings,
     sink: Sink,
-) -> impl Subscriber + Send + Sync
+    tracer_provider: Option<&SdkTracerProvider>,
+) -> Result<impl Subscriber + Send + Sync, InitError>
 where
     Sink: for<'a> MakeWriter<'a> + Send + Sync + 'static,
     // This "weird" syntax is a higher-ranked trait bound (HRTB)
@@ -29,14 +58,42 @@ where
     // for more details.
 {
     let env_filter =
-        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&settings.filter));
+    // The filter stays out of the vector: it must apply to every layer.
+    let mut layers: Vec<Box<dyn Layer<Layered<EnvFilter, Registry>> + Send + Sync>> = Vec::new();
+
+    let writer = match &settings.file {
+        Some(file) => BoxMakeWriter::new(sink.and(file.appender()?)),
+        None => BoxMakeWriter::new(sink),
+    };
+    let span_events = if settings.span_events {
+        FmtSpan::NEW | FmtSpan::CLOSE
+    } else {
+        FmtSpan::NONE
+    };
+    let fmt_layer = fmt::layer()
+        .with_span_events(span_events)
+        // No escape codes in the log file.
+        .with_ansi(settings.file.is_none());
+    match settings.format {
+        LogFormat::Pretty => layers.push(fmt_layer.pretty().with_writer(writer).boxed()),
+        LogFormat::Compact => layers.push(fmt_layer.compact().with_writer(writer).boxed()),
+        LogFormat::Json => layers.push(fmt_layer.json().with_writer(writer).boxed()),
+        LogFormat::Bunyan => {
+            layers.push(JsonStorageLayer.boxed());
+            layers.push(BunyanFormattingLayer::new(name.clone(), writer).boxed());
+        }
+    }
 
-    let formatting_layer = BunyanFormattingLayer::new(name, sink);
+    if let Some(provider) = tracer_provider {
+        layers.push(
+            tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer(name))
+                .boxed(),
+        );
+    }
 
-    Registry::default()
-        .with(env_filter)
-        .with(JsonStorageLayer)
-        .with(formatting_layer)
+    Ok(Registry::default().with(env_filter).with(layers))
 }
 
 /// Register a subscriber as global default to process span data.
@@ -45,4 +102,321 @@ where
 pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     LogTracer::init().expect("Failed to set logger");
     set_global_default(subscriber).expect("Failed to set subscriber");
+    // Read from incoming `traceparent` headers by `TracingLogger`, written
+    // to outgoing ones by `inject_trace_context`.
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+}
+
+tokio::task_local! {
+    static PII_REDACTION: PiiRedaction;
+}
+
+/// How the values wrapped in `Pii` are recorded by an application: as they
+/// are, or hashed with a secret key.
+///
+/// It applies to the requests and the deliveries the application handles,
+/// see `PiiRedaction::scope`. Anything else records values as they are.
+#[derive(Clone)]
+pub struct PiiRedaction {
+    key: Option<Hmac<Sha256>>,
+}
+
+impl PiiRedaction {
+    pub fn disabled() -> Self {
+        Self { key: None }
+    }
+
+    pub fn with_key(key: &Secret<String>) -> Self {
+        let key = Hmac::<Sha256>::new_from_slice(key.expose_secret().as_bytes()).unwrap();
+        Self { key: Some(key) }
+    }
+
+    /// The redaction of the request being handled, to be carried over to
+    /// the tasks it spawns.
+    pub fn current() -> Self {
+        PII_REDACTION
+            .try_with(Clone::clone)
+            .unwrap_or_else(|_| Self::disabled())
+    }
+
+    /// Run `f` with `Pii` values recorded according to `self`.
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        PII_REDACTION.scope(self, f).await
+    }
+
+    /// A keyed hash of `value`: without the key, it cannot be reversed by
+    /// hashing a list of known email addresses.
+    fn redact(&self, value: &str) -> Option<String> {
+        let mut mac = self.key.clone()?;
+        mac.update(value.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        Some(format!("redacted:{}", hex::encode(&digest[..8])))
+    }
+}
+
+/// Apply the application's `PiiRedaction` to the request.
+pub async fn scope_pii_redaction(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let redaction = req
+        .app_data::<Data<PiiRedaction>>()
+        .map(|redaction| redaction.get_ref().clone())
+        .unwrap_or_else(PiiRedaction::disabled);
+    redaction.scope(next.call(req)).await
+}
+
+/// An email address or a name, to be recorded in a span or a log line.
+///
+/// With redaction on, it is shown as a short keyed hash of the value: the
+/// lines about a given subscriber can still be matched up.
+pub struct Pii<T>(pub T);
+
+impl<T: Display> Display for Pii<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let redacted = PII_REDACTION
+            .try_with(|redaction| redaction.redact(&self.0.to_string()))
+            .ok()
+            .flatten();
+        match redacted {
+            Some(redacted) => redacted.fmt(f),
+            None => self.0.fmt(f),
+        }
+    }
+}
+
+/// Add the W3C trace context of the current span to an outgoing request, so
+/// that the spans of the service it calls join the same trace.
+pub fn inject_trace_context(request: RequestBuilder) -> RequestBuilder {
+    let context = tracing::Span::current().context();
+    let mut headers = HeaderMap::new();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(&mut headers))
+    });
+    request.headers(headers)
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Run a CPU-heavy closure on tokio's blocking thread pool.
+///
+/// The closure is executed inside the span that is current at the time of
+/// the call, so that anything it logs is still attached to the request
+/// that triggered it.
+pub fn spawn_blocking_with_tracing<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let current_span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || current_span.in_scope(f))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_subscriber, Pii, PiiRedaction};
+    use crate::configuration::{
+        LogFileSettings, LogFormat, LogRotation, LogSettings, OtlpExporterSettings,
+        TelemetrySettings,
+    };
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailClient, PostmarkTransport, RetryPolicy};
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use prost::Message;
+    use secrecy::Secret;
+    use std::time::Duration;
+    use tracing_actix_web::TracingLogger;
+    use uuid::Uuid;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn log_settings(format: LogFormat, file: Option<LogFileSettings>) -> LogSettings {
+        LogSettings {
+            format,
+            filter: "info".into(),
+            span_events: false,
+            file,
+        }
+    }
+
+    #[test]
+    fn logs_are_also_written_to_the_log_file() {
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let file = LogFileSettings {
+            directory: directory.to_string_lossy().into_owned(),
+            file_name_prefix: "zero2prod".into(),
+            rotation: LogRotation::Never,
+        };
+        let subscriber = get_subscriber(
+            "test".into(),
+            &log_settings(LogFormat::Json, Some(file)),
+            std::io::sink,
+            None,
+        )
+        .unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(answer = 42, "Logged to a file");
+        });
+
+        let contents = std::fs::read_to_string(directory.join("zero2prod.log")).unwrap();
+        let line: serde_json::Value = serde_json::from_str(contents.trim()).unwrap();
+        assert_eq!(line["fields"]["message"], "Logged to a file");
+        assert_eq!(line["fields"]["answer"], 42);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn redacted_values_can_be_matched_up_but_not_read() {
+        let redaction = PiiRedaction::with_key(&Secret::new("key".into()));
+        let (redacted, again, other) = redaction
+            .scope(async {
+                (
+                    Pii("ursula@example.com").to_string(),
+                    Pii("ursula@example.com").to_string(),
+                    Pii("le_guin@example.com").to_string(),
+                )
+            })
+            .await;
+        let with_another_key = PiiRedaction::with_key(&Secret::new("other key".into()))
+            .scope(async { Pii("ursula@example.com").to_string() })
+            .await;
+
+        assert!(!redacted.contains("ursula"));
+        assert_eq!(redacted, again);
+        assert_ne!(redacted, other);
+        assert_ne!(redacted, with_another_key);
+        assert_eq!(Pii("ursula@example.com").to_string(), "ursula@example.com");
+    }
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    async fn send_email(email_client: web::Data<EmailClient>) -> HttpResponse {
+        let recipient = SubscriberEmail::parse("ursula@example.com".into()).unwrap();
+        email_client
+            .send_email(&recipient, "Subject", "<p>Body</p>", "Body", None)
+            .await
+            .unwrap();
+        HttpResponse::Ok().finish()
+    }
+
+    #[tokio::test]
+    async fn spans_are_exported_and_the_trace_context_is_propagated() {
+        // Arrange
+        let collector = MockServer::start().await;
+        Mock::given(path("/v1/traces"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&collector)
+            .await;
+        let postmark = MockServer::start().await;
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&postmark)
+            .await;
+
+        let settings = TelemetrySettings {
+            service_name: "zero2prod".into(),
+            log: log_settings(LogFormat::Bunyan, None),
+            otlp_exporter: OtlpExporterSettings {
+                enabled: true,
+                endpoint: format!("{}/v1/traces", collector.uri()),
+                timeout_milliseconds: 1000,
+            },
+            redact_pii: false,
+            pii_hashing_key: Secret::new("key".into()),
+        };
+        let tracer_provider = settings.tracer_provider().unwrap().unwrap();
+        let subscriber = get_subscriber(
+            "test".into(),
+            &log_settings(LogFormat::Bunyan, None),
+            std::io::sink,
+            Some(&tracer_provider),
+        )
+        .unwrap();
+        // Everything below runs on this thread: no need for a global subscriber.
+        let _default = tracing::subscriber::set_default(subscriber);
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let email_client = EmailClient::new(
+            SubscriberEmail::parse("newsletter@example.com".into()).unwrap(),
+            Box::new(
+                PostmarkTransport::new(
+                    postmark.uri(),
+                    Secret::new("token".into()),
+                    Duration::from_secs(1),
+                )
+                .unwrap(),
+            ),
+            RetryPolicy {
+                max_attempts: 1,
+                base_delay: Duration::ZERO,
+                max_delay: Duration::ZERO,
+                jitter: Duration::ZERO,
+            },
+        );
+        let app = init_service(
+            App::new()
+                .wrap(TracingLogger::default())
+                .app_data(web::Data::new(email_client))
+                .route("/", web::get().to(send_email)),
+        )
+        .await;
+        let request = TestRequest::get()
+            .uri("/")
+            .insert_header((
+                "traceparent",
+                format!("00-{}-00f067aa0ba902b7-01", TRACE_ID),
+            ))
+            .to_request();
+
+        // Act
+        // The request span is only closed once the response is dropped.
+        let status = call_service(&app, request).await.status();
+        tracer_provider.force_flush().unwrap();
+
+        // Assert
+        assert!(status.is_success());
+
+        let outgoing = &postmark.received_requests().await.unwrap()[0];
+        let traceparent = outgoing.headers.get("traceparent").unwrap();
+        assert!(traceparent
+            .to_str()
+            .unwrap()
+            .starts_with(&format!("00-{}-", TRACE_ID)));
+
+        let exported: Vec<String> = collector
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .flat_map(|request| {
+                ExportTraceServiceRequest::decode(request.body.as_slice())
+                    .unwrap()
+                    .resource_spans
+            })
+            .flat_map(|resource_spans| resource_spans.scope_spans)
+            .flat_map(|scope_spans| scope_spans.spans)
+            .filter(|span| hex::encode(&span.trace_id) == TRACE_ID)
+            .map(|span| span.name)
+            .collect();
+        // The request span is named after the route it matched.
+        assert!(exported.contains(&"GET /".to_string()));
+        assert!(exported.contains(&"Sending email".to_string()));
+    }
 }
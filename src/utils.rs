@@ -0,0 +1,68 @@
+use actix_web::http::header::{Accept, Header, LOCATION};
+use actix_web::{HttpRequest, HttpResponse};
+use rand::{distributions::Alphanumeric, Rng};
+
+/// Return an opaque 500 while preserving the error root cause for logging.
+pub fn e500<T>(e: T) -> actix_web::Error
+where
+    T: std::fmt::Debug + std::fmt::Display + 'static,
+{
+    actix_web::error::ErrorInternalServerError(e)
+}
+
+pub fn see_other(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, location))
+        .finish()
+}
+
+/// Whether the client would rather get an HTML page than JSON back.
+///
+/// Browsers rank `text/html` first; API clients either ask for JSON or
+/// send no preference at all.
+pub fn prefers_html(request: &HttpRequest) -> bool {
+    Accept::parse(request).is_ok_and(|accept| accept.preference().essence_str() == "text/html")
+}
+
+/// Escape user input before interpolating it into an HTML page.
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Generate a random, URL-safe token for one-off links sent by email.
+pub fn generate_token() -> String {
+    let rng = rand::thread_rng();
+
+    rng.sample_iter(&Alphanumeric)
+        .take(25)
+        .map(char::from)
+        .collect()
+}
+
+/// Format an error followed by every error in its `source` chain.
+///
+/// Meant for `Debug` implementations, so that the one log line emitted for
+/// a failed request carries the root cause as well.
+pub fn error_chain_fmt(
+    e: &impl std::error::Error,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    writeln!(f, "{}\n", e)?;
+    let mut current = e.source();
+    while let Some(cause) = current {
+        writeln!(f, "Caused by:\n\t{}", cause)?;
+        current = cause.source();
+    }
+    Ok(())
+}
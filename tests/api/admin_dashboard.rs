@@ -0,0 +1,65 @@
+use crate::helpers::{assert_is_redirect_to, hash_password, spawn_app, spawn_app_with};
+use secrecy::Secret;
+use zero2prod::configuration::AdminSettings;
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_access_the_admin_dashboard() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app.get_admin_dashboard().await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn logout_clears_session_state() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act - Part 1 - Login
+    app.login_as_test_user().await;
+
+    // Act - Part 2 - Follow the redirect
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(html_page.contains(&format!("Welcome {}", app.test_user.username)));
+
+    // Act - Part 3 - Logout
+    let response = app.post_logout().await;
+    assert_is_redirect_to(&response, "/login");
+
+    // Act - Part 4 - Follow the redirect
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains(r#"<p><i>You have successfully logged out.</i></p>"#));
+
+    // Act - Part 5 - Attempt to load admin panel
+    let response = app.get_admin_dashboard().await;
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn the_username_is_escaped_on_the_dashboard() {
+    // Arrange
+    let app = spawn_app_with(|settings| {
+        settings.admin = Some(AdminSettings {
+            username: "<b>admin</b>".into(),
+            password_hash: Secret::new(hash_password("a-password-only-they-know")),
+            email: None,
+        });
+    })
+    .await;
+    app.post_login(&serde_json::json!({
+        "username": "<b>admin</b>",
+        "password": "a-password-only-they-know"
+    }))
+    .await;
+
+    // Act
+    let html_page = app.get_admin_dashboard_html().await;
+
+    // Assert
+    assert!(html_page.contains("Welcome &lt;b&gt;admin&lt;/b&gt;!"));
+    assert!(!html_page.contains("<b>admin</b>"));
+}
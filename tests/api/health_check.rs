@@ -1,20 +1,138 @@
 use crate::helpers::spawn_app;
 use reqwest::Client;
+use sqlx::{Connection, Executor, PgConnection};
+use wiremock::matchers::{header, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::configuration::get_configuration;
 
 #[tokio::test]
-async fn health_check_works() {
+async fn liveness_works() {
     let test_app = spawn_app().await;
 
     let client = Client::new();
 
     let response = client
-        .get(&format!("{}/health_check", &test_app.address))
+        .get(format!("{}/health/live", &test_app.address))
         .send()
         .await
         .expect("Failed to execute request.");
 
     assert!(response.status().is_success());
-    assert_eq!(Some(370), response.content_length());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body, serde_json::json!({"status": "up"}));
+}
+
+#[tokio::test]
+async fn readiness_reports_each_component() {
+    // Arrange
+    let test_app = spawn_app().await;
+    Mock::given(path("/server"))
+        .and(method("GET"))
+        .and(header("X-Postmark-Server-Token", "POSTMARK_API_TOKEN"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let response = test_app.get_readiness().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "status": "up",
+            "components": {
+                "database": {"status": "up", "required": true},
+                "email_provider": {"status": "up", "required": false},
+            }
+        })
+    );
+}
+
+#[tokio::test]
+async fn readiness_is_not_lost_when_an_informational_check_fails() {
+    // Arrange
+    let test_app = spawn_app().await;
+    Mock::given(path("/server"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let response = test_app.get_readiness().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "up");
+    // Why it failed is logged, not shown to whoever asks
+    assert_eq!(
+        body["components"]["email_provider"],
+        serde_json::json!({"status": "down", "required": false})
+    );
+}
+
+#[tokio::test]
+async fn the_email_provider_status_is_reused_for_a_while() {
+    // Arrange
+    let test_app = spawn_app().await;
+    Mock::given(path("/server"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    for _ in 0..3 {
+        let response = test_app.get_readiness().await;
+
+        // Assert
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["components"]["email_provider"]["status"], "down");
+    }
+}
+
+#[tokio::test]
+async fn readiness_fails_when_the_database_is_down() {
+    // Arrange
+    let test_app = spawn_app().await;
+    Mock::given(path("/server"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+    // Refuse new connections and close the ones the application holds
+    let database_name: String = sqlx::query_scalar!(r#"SELECT current_database() AS "name!""#)
+        .fetch_one(&test_app.connection_pool)
+        .await
+        .unwrap();
+    let configuration = get_configuration().unwrap();
+    let mut connection = PgConnection::connect_with(&configuration.database.without_db())
+        .await
+        .unwrap();
+    connection
+        .execute(
+            format!(
+                r#"ALTER DATABASE "{0}" ALLOW_CONNECTIONS false;
+                SELECT pg_terminate_backend(pid) FROM pg_stat_activity WHERE datname = '{0}';"#,
+                database_name
+            )
+            .as_str(),
+        )
+        .await
+        .unwrap();
+
+    // Act
+    let response = test_app.get_readiness().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 503);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "down");
+    assert_eq!(body["components"]["database"]["status"], "down");
+    assert_eq!(body["components"]["email_provider"]["status"], "up");
 }
 
 #[tokio::test]
@@ -24,11 +142,15 @@ async fn homepage_works() {
     let client = Client::new();
 
     let response = client
-        .get(&format!("{}/", &test_app.address))
+        .get(format!("{}/", &test_app.address))
         .send()
         .await
         .expect("Failed to execute request.");
 
     assert!(response.status().is_success());
-    assert_eq!(Some(534), response.content_length());
+    assert_eq!(Some(1489), response.content_length());
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"name="form_timestamp""#));
+    assert!(html.contains(r#"name="form_signature""#));
+    assert!(html.contains(r#"name="website""#));
 }
@@ -1,29 +1,111 @@
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
 use once_cell::sync::Lazy;
+use secrecy::{ExposeSecret, Secret};
 use sqlx::{Connection, Executor, PgConnection, PgPool};
+use std::sync::Mutex;
+use tracing_subscriber::fmt::writer::MakeWriterExt;
 use uuid::Uuid;
 use wiremock::MockServer;
-use zero2prod::configuration::{get_configuration, DatabaseSettings};
+use zero2prod::anti_bot::FormTimestampSigner;
+use zero2prod::configuration::{
+    get_configuration, DatabaseSettings, EmailTransportSettings, LogFormat, LogSettings, Settings,
+};
+use zero2prod::email_client::EmailClient;
+use zero2prod::issue_delivery_worker::{try_execute_task, ExecutionOutcome};
 use zero2prod::startup::{get_connection_pool, Application};
-use zero2prod::telemetry::{get_subscriber, init_subscriber};
+use zero2prod::subscription_email_worker;
+use zero2prod::telemetry::{get_subscriber, init_subscriber, PiiRedaction};
 
 // Ensure that the `tracing` stack is only initialised once using `once_cell`
 static TRACING: Lazy<()> = Lazy::new(|| {
-    let default_filter_level = "info".to_string();
+    let log_settings = LogSettings {
+        format: LogFormat::Bunyan,
+        filter: "info".into(),
+        span_events: false,
+        file: None,
+    };
     let subscriber_name = "test".to_string();
     if std::env::var("TEST_LOG").is_ok() {
-        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::stdout);
+        let sink = (|| LogCapture).and(std::io::stdout);
+        let subscriber = get_subscriber(subscriber_name, &log_settings, sink, None).unwrap();
         init_subscriber(subscriber);
     } else {
-        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::sink);
+        let subscriber =
+            get_subscriber(subscriber_name, &log_settings, || LogCapture, None).unwrap();
         init_subscriber(subscriber);
     }
 });
 
+/// What every test app has logged so far, for the tests that check it.
+static CAPTURED_LOGS: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+struct LogCapture;
+
+impl std::io::Write for LogCapture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        CAPTURED_LOGS.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The log lines of every test app, as bunyan JSON, one per line.
+///
+/// Tests run side by side: look for values unique to the test.
+pub fn captured_logs() -> String {
+    String::from_utf8_lossy(&CAPTURED_LOGS.lock().unwrap()).into_owned()
+}
+
 pub struct TestApp {
     pub address: String,
     pub connection_pool: sqlx::PgPool,
     pub email_server: MockServer,
     pub port: u16,
+    pub test_user: TestUser,
+    pub api_client: reqwest::Client,
+    pub email_client: EmailClient,
+    pub form_signer: FormTimestampSigner,
+    pub metrics_token: Secret<String>,
+    pub send_already_subscribed_email: bool,
+    /// What the app puts in the links it sends.
+    pub base_url: String,
+    pub pii_redaction: PiiRedaction,
+}
+
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String,
+    pub email: String,
+}
+
+impl TestUser {
+    pub fn generate() -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+            email: format!("{}@example.com", Uuid::new_v4()),
+        }
+    }
+
+    async fn store(&self, connection_pool: &PgPool) {
+        let password_hash = hash_password(&self.password);
+        sqlx::query!(
+            "INSERT INTO users (user_id, username, password_hash, email) VALUES ($1, $2, $3, $4)",
+            self.user_id,
+            self.username,
+            password_hash,
+            self.email,
+        )
+        .execute(connection_pool)
+        .await
+        .expect("Failed to store test user.");
+    }
 }
 
 pub struct ConfirmationLinks {
@@ -32,52 +114,289 @@ pub struct ConfirmationLinks {
 }
 
 impl TestApp {
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                try_execute_task(&self.connection_pool, &self.email_client, &self.address)
+                    .await
+                    .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    /// Send the emails queued by signups, as the background worker would.
+    pub async fn dispatch_subscription_emails(&self) {
+        loop {
+            let outcome = self
+                .pii_redaction
+                .clone()
+                .scope(subscription_email_worker::try_execute_task(
+                    &self.connection_pool,
+                    &self.email_client,
+                    &self.base_url,
+                    self.send_already_subscribed_email,
+                ))
+                .await;
+            if let ExecutionOutcome::EmptyQueue = outcome.unwrap() {
+                break;
+            }
+        }
+    }
+
+    /// Submit the subscribe form like a human would: with a valid render
+    /// timestamp from a minute ago and an empty honeypot. The email it
+    /// queues is sent before returning.
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
+        let body = format!("{}&{}", body, self.bot_check_fields(60));
+        let response = self.post_subscriptions_raw(body).await;
+        self.dispatch_subscription_emails().await;
+        response
+    }
+
+    pub async fn post_subscriptions_raw(&self, body: String) -> reqwest::Response {
         let client = reqwest::Client::new();
         client
-            .post(&format!("{}/subscriptions", &self.address))
+            .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
             .await
             .expect("Failed to execute request.")
     }
+
+    /// The signed timestamp of a form rendered `seconds_ago`.
+    pub fn bot_check_fields(&self, seconds_ago: i64) -> String {
+        let signed = self
+            .form_signer
+            .sign(chrono::Utc::now().timestamp() - seconds_ago);
+        format!(
+            "form_timestamp={}&form_signature={}",
+            signed.timestamp, signed.signature
+        )
+    }
+
+    /// Confirm on the page an unsubscribe link leads to.
+    pub async fn post_unsubscribe(&self, unsubscribe_link: reqwest::Url) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(unsubscribe_link)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("List-Unsubscribe=One-Click")
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_login<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/login", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_login_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/login", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_admin_dashboard(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/dashboard", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_readiness(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/health/ready", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_metrics(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/metrics", &self.address))
+            .bearer_auth(self.metrics_token.expose_secret())
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_dashboard_html(&self) -> String {
+        self.get_admin_dashboard().await.text().await.unwrap()
+    }
+
+    pub async fn post_logout(&self) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/logout", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_change_password(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/password", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_change_password_html(&self) -> String {
+        self.get_change_password().await.text().await.unwrap()
+    }
+
+    pub async fn post_change_password<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/admin/password", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_password_reset<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/password/reset", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_password_reset_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/password/reset", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_password_reset_confirm<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/password/reset/confirm", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn login_as_test_user(&self) {
+        self.post_login(&serde_json::json!({
+            "username": &self.test_user.username,
+            "password": &self.test_user.password,
+        }))
+        .await;
+    }
+
+    pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
+        self.post_newsletters_with_key(body, &Uuid::new_v4().to_string())
+            .await
+    }
+
+    pub async fn post_newsletters_with_key(
+        &self,
+        body: serde_json::Value,
+        idempotency_key: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .header("Idempotency-Key", idempotency_key)
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Extract the single link in the email body that is not the
+    /// unsubscribe link every email carries in its footer.
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
+        self.get_links(email_request, |link| {
+            link.path() != "/subscriptions/unsubscribe"
+        })
+    }
+
+    pub fn get_unsubscribe_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
+        self.get_links(email_request, |link| {
+            link.path() == "/subscriptions/unsubscribe"
+        })
+    }
+
+    fn get_links(
+        &self,
+        email_request: &wiremock::Request,
+        filter: impl Fn(&reqwest::Url) -> bool,
+    ) -> ConfirmationLinks {
         let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
         let get_link = |s: &str| {
             let links: Vec<_> = linkify::LinkFinder::new()
                 .links(s)
                 .filter(|l| *l.kind() == linkify::LinkKind::Url)
+                .map(|l| reqwest::Url::parse(l.as_str()).unwrap())
+                .filter(&filter)
                 .collect();
             assert_eq!(links.len(), 1);
-            let raw_link = links[0].as_str().to_owned();
-            let mut confirmation_link = reqwest::Url::parse(&raw_link).unwrap();
+            let mut confirmation_link = links[0].clone();
             confirmation_link.set_port(Some(self.port)).unwrap();
             confirmation_link
         };
 
-        let html = get_link(&body["HtmlBody"].as_str().unwrap());
-        let plain_text = get_link(&body["TextBody"].as_str().unwrap());
+        let html = get_link(body["HtmlBody"].as_str().unwrap());
+        let plain_text = get_link(body["TextBody"].as_str().unwrap());
 
         ConfirmationLinks { html, plain_text }
     }
 }
 
 pub async fn spawn_app() -> TestApp {
-    Lazy::force(&TRACING);
-
-    let email_server = MockServer::start().await;
+    spawn_app_with(|_| {}).await
+}
 
-    let settings = {
-        let mut s = get_configuration().expect("Failed to read configuration.");
-        s.database.database_name = Uuid::new_v4().to_string();
-        s.application.port = 0;
-        s.email_client.base_url = email_server.uri();
-        s
-    };
+/// Like `spawn_app`, with `customise` applied to the settings first.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    // Match the parameters of `compute_password_hash`
+    Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(15000, 2, 1, None).unwrap(),
+    )
+    .hash_password(password.as_bytes(), &salt)
+    .unwrap()
+    .to_string()
+}
 
-    // create and migrate db
-    configure_database(&settings.database).await;
+pub async fn spawn_app_with(customise: impl FnOnce(&mut Settings)) -> TestApp {
+    let email_server = MockServer::start().await;
+    let mut settings = test_settings(&email_server).await;
+    customise(&mut settings);
 
     let application = Application::build(settings.clone())
         .await
@@ -85,16 +404,54 @@ pub async fn spawn_app() -> TestApp {
 
     let application_port = application.port();
 
-    let connection_pool = get_connection_pool(&settings.database);
+    let connection_pool = get_connection_pool(&settings);
     let address = format!("http://localhost:{}", application_port);
+    #[allow(clippy::let_underscore_future)]
     let _ = tokio::spawn(application.server);
 
-    TestApp {
+    let api_client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .cookie_store(true)
+        .build()
+        .unwrap();
+
+    let test_app = TestApp {
         address,
         connection_pool,
         email_server,
         port: application_port,
-    }
+        test_user: TestUser::generate(),
+        api_client,
+        email_client: settings.email_client.client(),
+        form_signer: settings.subscribe_form.signer(),
+        metrics_token: settings.metrics.bearer_token.clone(),
+        send_already_subscribed_email: settings.application.send_already_subscribed_email,
+        base_url: settings.application.base_url.clone(),
+        pii_redaction: settings.telemetry.pii_redaction(),
+    };
+    test_app.test_user.store(&test_app.connection_pool).await;
+    test_app
+}
+
+/// Settings pointing at a new, migrated database, a random port and
+/// `email_server` as the email provider.
+pub async fn test_settings(email_server: &MockServer) -> Settings {
+    Lazy::force(&TRACING);
+
+    let settings = {
+        let mut s = get_configuration().expect("Failed to read configuration.");
+        s.database.database_name = Uuid::new_v4().to_string();
+        s.application.port = 0;
+        s.email_client.transport = EmailTransportSettings::Postmark {
+            base_url: email_server.uri(),
+            authorization_token: Secret::new("POSTMARK_API_TOKEN".into()),
+        };
+        s
+    };
+
+    // create and migrate db
+    configure_database(&settings.database).await;
+    settings
 }
 
 pub async fn configure_database(config: &DatabaseSettings) -> PgPool {
@@ -120,3 +477,8 @@ pub async fn configure_database(config: &DatabaseSettings) -> PgPool {
 
     connection_pool
 }
+
+pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(response.headers().get("Location").unwrap(), location);
+}
@@ -0,0 +1,105 @@
+use crate::helpers::{assert_is_redirect_to, hash_password, spawn_app, spawn_app_with};
+use secrecy::Secret;
+use zero2prod::configuration::AdminSettings;
+
+#[tokio::test]
+async fn an_error_flash_message_is_set_on_failure() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let login_body = serde_json::json!({
+        "username": "random-username",
+        "password": "random-password"
+    });
+    let response = app.post_login(&login_body).await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+
+    // Act - Part 2 - Follow the redirect
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains("<p><i>Authentication failed</i></p>"));
+
+    // Act - Part 3 - Reload the login page
+    let html_page = app.get_login_html().await;
+    assert!(!html_page.contains("<p><i>Authentication failed</i></p>"));
+}
+
+#[tokio::test]
+async fn redirect_to_admin_dashboard_after_login_success() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act - Part 1 - Login
+    let login_body = serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    });
+    let response = app.post_login(&login_body).await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
+
+    // Act - Part 2 - Follow the redirect
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(html_page.contains(&format!("Welcome {}", app.test_user.username)));
+}
+
+#[tokio::test]
+async fn sessions_are_stored_in_postgres() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    app.login_as_test_user().await;
+
+    // Assert
+    let saved = sqlx::query!("SELECT state FROM sessions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .expect("Failed to fetch saved session.");
+    // actix-session stores every value as a JSON-encoded string
+    let expected_user_id = format!(r#""{}""#, app.test_user.user_id);
+    assert_eq!(
+        saved.state["user_id"].as_str(),
+        Some(expected_user_id.as_str())
+    );
+}
+
+#[tokio::test]
+async fn the_configured_admin_can_log_in() {
+    // Arrange
+    let app = spawn_app_with(|settings| {
+        settings.admin = Some(AdminSettings {
+            username: "first-admin".into(),
+            password_hash: Secret::new(hash_password("a-password-only-they-know")),
+            email: None,
+        });
+    })
+    .await;
+
+    // Act
+    let login_body = serde_json::json!({
+        "username": "first-admin",
+        "password": "a-password-only-they-know"
+    });
+    let response = app.post_login(&login_body).await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/admin/dashboard");
+}
+
+#[tokio::test]
+async fn the_formerly_seeded_admin_cannot_log_in() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let login_body = serde_json::json!({
+        "username": "admin",
+        "password": "everythinghastostartsomewhere"
+    });
+    let response = app.post_login(&login_body).await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+}
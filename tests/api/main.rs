@@ -1,4 +1,13 @@
+mod admin_dashboard;
+mod change_password;
 mod health_check;
 mod helpers;
+mod login;
+mod metrics;
+mod newsletters;
+mod password_reset;
+mod request_id;
+mod shutdown;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_unsubscribe;
@@ -0,0 +1,61 @@
+use crate::helpers::spawn_app;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[tokio::test]
+async fn metrics_require_the_bearer_token() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let anonymous = client
+        .get(format!("{}/metrics", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let wrong_token = client
+        .get(format!("{}/metrics", &app.address))
+        .bearer_auth("not-the-token")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(anonymous.status().as_u16(), 401);
+    assert_eq!(wrong_token.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn metrics_expose_http_pool_and_email_metrics() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    // Act
+    let response = app.get_metrics().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.headers()["Content-Type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    let body = response.text().await.unwrap();
+    assert!(
+        body.contains(r#"http_requests_total{method="POST",route="/subscriptions",status="200"}"#)
+    );
+    assert!(body
+        .contains(r#"http_request_duration_seconds_bucket{method="POST",route="/subscriptions""#));
+    assert!(body.contains("db_pool_connections "));
+    assert!(body.contains("db_pool_idle_connections "));
+    assert!(body.contains("emails_sent_total "));
+    assert!(body.contains("emails_failed_total "));
+    assert!(body.contains("email_send_duration_seconds_count "));
+}
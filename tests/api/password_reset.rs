@@ -0,0 +1,286 @@
+use crate::helpers::{assert_is_redirect_to, hash_password, spawn_app, spawn_app_with, TestApp};
+use secrecy::Secret;
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::configuration::AdminSettings;
+
+const GENERIC_MESSAGE: &str = "<p><i>If an account with that email exists, \
+    we have sent it a link to reset the password.</i></p>";
+
+async fn request_reset_link(app: &TestApp) -> reqwest::Url {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_password_reset(&serde_json::json!({ "email": &app.test_user.email }))
+        .await;
+    assert_is_redirect_to(&response, "/password/reset");
+
+    let email_requests = wait_for_emails(app, 1).await;
+    app.get_confirmation_links(email_requests.last().unwrap())
+        .html
+}
+
+/// The link is sent after the response: wait until `count` emails are out.
+async fn wait_for_emails(app: &TestApp, count: usize) -> Vec<wiremock::Request> {
+    for _ in 0..100 {
+        let email_requests = app.email_server.received_requests().await.unwrap();
+        if email_requests.len() >= count {
+            return email_requests;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("Expected {} emails to be sent", count);
+}
+
+fn token_from(link: &reqwest::Url) -> String {
+    link.query_pairs()
+        .find(|(k, _)| k == "password_reset_token")
+        .map(|(_, v)| v.into_owned())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn requesting_a_reset_sends_an_email_with_a_link() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let reset_link = request_reset_link(&app).await;
+
+    // Assert
+    assert_eq!(reset_link.host_str().unwrap(), "127.0.0.1");
+    assert_eq!(reset_link.path(), "/password/reset/confirm");
+    let html_page = app.get_password_reset_html().await;
+    assert!(html_page.contains(GENERIC_MESSAGE));
+}
+
+#[tokio::test]
+async fn requesting_a_reset_for_an_unknown_email_looks_the_same_but_sends_nothing() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_password_reset(&serde_json::json!({ "email": "nobody@example.com" }))
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/password/reset");
+    let html_page = app.get_password_reset_html().await;
+    assert!(html_page.contains(GENERIC_MESSAGE));
+}
+
+#[tokio::test]
+async fn the_reset_link_lets_you_choose_a_new_password() {
+    // Arrange
+    let app = spawn_app().await;
+    let reset_link = request_reset_link(&app).await;
+    let new_password = Uuid::new_v4().to_string();
+
+    // Act - Part 1 - The link renders a form
+    let html_page = reqwest::get(reset_link.clone())
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(html_page.contains(&token_from(&reset_link)));
+
+    // Act - Part 2 - Submit a new password
+    let response = app
+        .post_password_reset_confirm(&serde_json::json!({
+            "password_reset_token": token_from(&reset_link),
+            "new_password": &new_password,
+            "new_password_check": &new_password,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/login");
+
+    // Act - Part 3 - Login using the new password
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": &app.test_user.username,
+            "password": &new_password,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
+}
+
+#[tokio::test]
+async fn a_reset_link_can_only_be_used_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let reset_link = request_reset_link(&app).await;
+    let new_password = Uuid::new_v4().to_string();
+    let body = serde_json::json!({
+        "password_reset_token": token_from(&reset_link),
+        "new_password": &new_password,
+        "new_password_check": &new_password,
+    });
+    let response = app.post_password_reset_confirm(&body).await;
+    assert_is_redirect_to(&response, "/login");
+
+    // Act
+    let response = app.post_password_reset_confirm(&body).await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/password/reset");
+    let html_page = app.get_password_reset_html().await;
+    assert!(html_page.contains("<p><i>This password reset link is invalid or has expired.</i></p>"));
+}
+
+#[tokio::test]
+async fn resetting_the_password_invalidates_the_other_reset_links() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    for count in 1..=2 {
+        app.post_password_reset(&serde_json::json!({ "email": &app.test_user.email }))
+            .await;
+        wait_for_emails(&app, count).await;
+    }
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let first_link = app.get_confirmation_links(&email_requests[0]).html;
+    let second_link = app.get_confirmation_links(&email_requests[1]).html;
+    let new_password = Uuid::new_v4().to_string();
+    let response = app
+        .post_password_reset_confirm(&serde_json::json!({
+            "password_reset_token": token_from(&second_link),
+            "new_password": &new_password,
+            "new_password_check": &new_password,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/login");
+
+    // Act
+    let response = app
+        .post_password_reset_confirm(&serde_json::json!({
+            "password_reset_token": token_from(&first_link),
+            "new_password": &new_password,
+            "new_password_check": &new_password,
+        }))
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/password/reset");
+}
+
+#[tokio::test]
+async fn an_expired_reset_link_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let reset_link = request_reset_link(&app).await;
+    sqlx::query!("UPDATE password_reset_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+    let new_password = Uuid::new_v4().to_string();
+
+    // Act
+    let response = app
+        .post_password_reset_confirm(&serde_json::json!({
+            "password_reset_token": token_from(&reset_link),
+            "new_password": &new_password,
+            "new_password_check": &new_password,
+        }))
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/password/reset");
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": &app.test_user.username,
+            "password": &new_password,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn resetting_the_password_logs_out_every_session() {
+    // Arrange
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+    let reset_link = request_reset_link(&app).await;
+    let new_password = Uuid::new_v4().to_string();
+
+    // Act
+    let response = app
+        .post_password_reset_confirm(&serde_json::json!({
+            "password_reset_token": token_from(&reset_link),
+            "new_password": &new_password,
+            "new_password_check": &new_password,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/login");
+
+    // Assert
+    let response = app.get_admin_dashboard().await;
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn requesting_resets_for_the_same_email_too_often_is_rate_limited() {
+    // Arrange
+    let app = spawn_app_with(|settings| {
+        settings.rate_limit.per_email.capacity = 1;
+    })
+    .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let body = serde_json::json!({ "email": &app.test_user.email });
+    let response = app.post_password_reset(&body).await;
+    assert_is_redirect_to(&response, "/password/reset");
+
+    // Act
+    let response = app.post_password_reset(&body).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn the_configured_admin_email_receives_reset_links() {
+    // Arrange
+    let app = spawn_app_with(|settings| {
+        settings.admin = Some(AdminSettings {
+            username: "first-admin".into(),
+            password_hash: Secret::new(hash_password(&Uuid::new_v4().to_string())),
+            email: Some("first-admin@example.com".into()),
+        });
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_password_reset(&serde_json::json!({ "email": "first-admin@example.com" }))
+        .await;
+
+    // Assert
+    let email_requests = wait_for_emails(&app, 1).await;
+    let body: serde_json::Value = serde_json::from_slice(&email_requests[0].body).unwrap();
+    assert_eq!(body["To"], "first-admin@example.com");
+}
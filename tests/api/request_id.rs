@@ -0,0 +1,96 @@
+use crate::helpers::{captured_logs, spawn_app};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[tokio::test]
+async fn responses_carry_a_generated_request_id() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(format!("{}/health/live", &app.address))
+        .await
+        .unwrap();
+
+    // Assert
+    let request_id = response.headers()["x-request-id"].to_str().unwrap();
+    assert!(Uuid::parse_str(request_id).is_ok());
+}
+
+#[tokio::test]
+async fn the_request_id_of_the_client_is_echoed_and_forwarded_to_the_email_provider() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let body = format!(
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&{}",
+        app.bot_check_fields(60)
+    );
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("X-Request-Id", "signup-4f2a")
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["x-request-id"], "signup-4f2a");
+    // The email is queued, and sent on behalf of the same request
+    app.dispatch_subscription_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    assert_eq!(email_request.headers["x-request-id"], "signup-4f2a");
+}
+
+#[tokio::test]
+async fn the_request_id_is_logged_on_the_request_span() {
+    // Arrange
+    let app = spawn_app().await;
+    let request_id = format!("logged-{}", Uuid::new_v4());
+
+    // Act
+    reqwest::Client::new()
+        .get(format!("{}/health/live", &app.address))
+        .header("X-Request-Id", &request_id)
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    let logs = captured_logs();
+    let request_span = logs
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .find(|line| line["x_request_id"] == request_id.as_str())
+        .expect("The request span was not logged with the request id.");
+    assert_eq!(request_span["http.target"], "/health/live");
+    assert_ne!(request_span["request_id"], request_id.as_str());
+}
+
+#[tokio::test]
+async fn an_unreasonable_request_id_is_replaced() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!("{}/health/live", &app.address))
+        .header("X-Request-Id", "a".repeat(500))
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    let request_id = response.headers()["x-request-id"].to_str().unwrap();
+    assert!(Uuid::parse_str(request_id).is_ok());
+}
@@ -0,0 +1,51 @@
+use crate::helpers::test_settings;
+use std::time::Duration;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use zero2prod::startup::Application;
+
+#[tokio::test]
+async fn shutdown_lets_in_flight_requests_finish_then_stops_accepting_connections() {
+    // Arrange
+    let email_server = MockServer::start().await;
+    let settings = {
+        let mut s = test_settings(&email_server).await;
+        s.application.shutdown_grace_period_seconds = 3;
+        s
+    };
+    let application = Application::build(settings)
+        .await
+        .expect("Failed to build application.");
+    let address = format!("http://localhost:{}", application.port());
+    let shutdown = application.shutdown_token();
+    let running = tokio::spawn(application.run_until_stopped());
+
+    // Keep a readiness probe busy while the shutdown starts
+    Mock::given(path("/server"))
+        .and(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+        .expect(1)
+        .mount(&email_server)
+        .await;
+    let in_flight = tokio::spawn(reqwest::get(format!("{}/health/ready", &address)));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // Act
+    shutdown.cancel();
+
+    // Assert
+    let response = in_flight
+        .await
+        .unwrap()
+        .expect("The in-flight request was dropped.");
+    assert_eq!(response.status().as_u16(), 200);
+
+    tokio::time::timeout(Duration::from_secs(10), running)
+        .await
+        .expect("The application did not stop.")
+        .unwrap()
+        .unwrap();
+    assert!(reqwest::get(format!("{}/health/live", &address))
+        .await
+        .is_err());
+}
@@ -1,6 +1,9 @@
-use crate::helpers::spawn_app;
-use wiremock::matchers::{method, path};
+use crate::helpers::{captured_logs, spawn_app, spawn_app_with};
+use secrecy::Secret;
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
 use wiremock::{Mock, ResponseTemplate};
+use zero2prod::telemetry::{Pii, PiiRedaction};
 
 #[tokio::test]
 pub async fn subscribe_returns_200_when_valid_form() {
@@ -22,6 +25,47 @@ pub async fn subscribe_returns_200_when_valid_form() {
     assert_eq!(200, response.status().as_u16());
 }
 
+#[tokio::test]
+async fn subscribe_returns_200_when_the_same_email_is_added_concurrently_in_another_case() {
+    // Arrange
+    let test_app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+    // A concurrent signup has inserted the row but not committed yet
+    let mut concurrent_signup = test_app.connection_pool.begin().await.unwrap();
+    sqlx::query!(
+        r#"INSERT INTO subscriptions (id, email, name, subscribed_at, status, unsubscribe_token)
+        VALUES ($1, 'RoyAbhishek77@Gmail.com', 'Abhishek Roy', now(), 'pending_confirmation', $2)"#,
+        Uuid::new_v4(),
+        Uuid::new_v4().to_string(),
+    )
+    .execute(&mut *concurrent_signup)
+    .await
+    .unwrap();
+
+    // Act
+    let (response, _) = tokio::join!(
+        test_app.post_subscriptions("name=Abhishek%20Roy&email=royabhishek77%40gmail.com".into()),
+        async {
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            concurrent_signup.commit().await.unwrap();
+        }
+    );
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_all(&test_app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].status, "pending_confirmation");
+}
+
 #[tokio::test]
 pub async fn subscribe_persists_the_new_subscriber() {
     // Arrange
@@ -137,7 +181,564 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
 
     // get intercepted request
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
 
     assert_eq!(confirmation_links.html, confirmation_links.plain_text);
 }
+
+#[tokio::test]
+async fn subscribing_twice_while_pending_resends_a_fresh_confirmation_link() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=Abhishek%20Roy&email=royabhishek77%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let first_response = app.post_subscriptions(body.into()).await;
+    let second_response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(first_response.status().as_u16(), 200);
+    assert_eq!(second_response.status().as_u16(), 200);
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let first_links = app.get_confirmation_links(&email_requests[0]);
+    let second_links = app.get_confirmation_links(&email_requests[1]);
+    assert_ne!(first_links.html, second_links.html);
+
+    // Only the latest link is still valid
+    let response = reqwest::get(first_links.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    let response = reqwest::get(second_links.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_all(&app.connection_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].status, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribe_returns_field_level_errors_as_json() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=Ursula%20%3CLe%20Guin%3E&email=ursula%40".into())
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "errors": [
+                {"field": "name", "message": "name contains forbidden character '<'"},
+                {"field": "email", "message": "email missing domain"},
+            ]
+        })
+    );
+}
+
+#[tokio::test]
+async fn subscribe_re_renders_the_form_with_inline_errors_for_browsers() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header(
+            "Accept",
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+        )
+        .body(format!(
+            "name=Ursula%20%3CLe%20Guin%3E&email=ursula_le_guin%40gmail.com&{}",
+            app.bot_check_fields(60)
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert!(response.headers()["Content-Type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let html = response.text().await.unwrap();
+    assert!(html.contains("<form"));
+    assert!(html.contains("name contains forbidden character &#x27;&lt;&#x27;"));
+    // What was typed is kept, escaped
+    assert!(html.contains(r#"value="Ursula &lt;Le Guin&gt;""#));
+    assert!(html.contains(r#"value="ursula_le_guin@gmail.com""#));
+}
+
+#[tokio::test]
+async fn subscribe_fails_if_there_is_a_fatal_database_error() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    // Sabotage the database
+    sqlx::query!("ALTER TABLE subscriptions DROP COLUMN email;")
+        .execute(&app.connection_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = app
+        .post_subscriptions_raw(format!("{}&{}", body, app.bot_check_fields(60)))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 500);
+    assert_eq!(response.text().await.unwrap(), "");
+}
+
+#[tokio::test]
+async fn subscribing_the_same_email_too_often_is_rate_limited() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=Abhishek%20Roy&email=royabhishek77%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(3)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Use up the burst allowed for one email
+    for _ in 0..3 {
+        let response = app.post_subscriptions(body.into()).await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    // Act - Part 2 - Same email, different case
+    let response = app
+        .post_subscriptions("name=Abhishek%20Roy&email=RoyAbhishek77%40gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 429);
+    let retry_after: u64 = response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0);
+    // Mock verifies on drop that no email was sent for the rejected request
+}
+
+#[tokio::test]
+async fn subscribing_too_often_from_one_client_is_rate_limited() {
+    // Arrange
+    let app = spawn_app().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Use up the burst allowed for one client
+    for i in 0..10 {
+        let body = format!("name=le%20guin&email=ursula{}%40gmail.com", i);
+        let response = app.post_subscriptions(body).await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    // Act - Part 2 - A forged X-Forwarded-For from an untrusted peer is ignored
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("X-Forwarded-For", "198.51.100.1")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 429);
+    assert!(response.headers().contains_key("Retry-After"));
+}
+
+#[tokio::test]
+async fn submissions_that_fill_the_honeypot_are_silently_dropped() {
+    // Arrange
+    let app = spawn_app().await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&website=http%3A%2F%2Fspam.example"
+                .into(),
+        )
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.connection_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_empty());
+    // Mock verifies on drop that no email was sent
+}
+
+#[tokio::test]
+async fn submissions_without_a_valid_form_timestamp_are_silently_dropped() {
+    // Arrange
+    let app = spawn_app().await;
+    let name_and_email = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    let test_cases = [
+        (
+            format!("{}&{}", name_and_email, app.bot_check_fields(0)),
+            "submitted as soon as rendered",
+        ),
+        (
+            format!("{}&{}", name_and_email, app.bot_check_fields(7 * 86_400)),
+            "a timestamp from a week ago",
+        ),
+        (
+            format!(
+                "{}&form_timestamp=1&form_signature=0123456789abcdef",
+                name_and_email
+            ),
+            "forged signature",
+        ),
+    ];
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    for (body, description) in test_cases {
+        // Act
+        let response = app.post_subscriptions_raw(body).await;
+
+        // Assert
+        assert_eq!(
+            200,
+            response.status().as_u16(),
+            "The API did not pretend to accept the submission when it had {}.",
+            description
+        );
+    }
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.connection_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_empty());
+    // Mock verifies on drop that no email was sent
+}
+
+#[tokio::test]
+async fn submissions_without_a_form_timestamp_are_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Accept", "application/json")
+        .form(&[("name", "le guin"), ("email", "ursula_le_guin@gmail.com")])
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["field"], "form_timestamp");
+}
+
+#[tokio::test]
+async fn subscribing_a_confirmed_email_looks_the_same_as_subscribing_a_new_one() {
+    // Arrange
+    let app =
+        spawn_app_with(|settings| settings.application.send_already_subscribed_email = true).await;
+    let body = "name=Abhishek%20Roy&email=royabhishek77%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    let new_response = app.post_subscriptions(body.into()).await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let confirmed_response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(
+        new_response.status().as_u16(),
+        confirmed_response.status().as_u16()
+    );
+    assert_eq!(
+        new_response.text().await.unwrap(),
+        confirmed_response.text().await.unwrap()
+    );
+
+    // The subscriber is told instead, without a confirmation link
+    let email_request = &app.email_server.received_requests().await.unwrap()[1];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Subject"], "You're already subscribed");
+    assert!(!body["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("/subscriptions/confirm"));
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn a_confirmed_email_and_a_new_one_only_queue_an_email_during_the_request() {
+    // Arrange
+    let app =
+        spawn_app_with(|settings| settings.application.send_already_subscribed_email = true).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(3)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=Abhishek%20Roy&email=royabhishek77%40gmail.com".into())
+        .await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let confirmed_response = app
+        .post_subscriptions_raw(format!(
+            "name=Abhishek%20Roy&email=royabhishek77%40gmail.com&{}",
+            app.bot_check_fields(60)
+        ))
+        .await;
+    let new_response = app
+        .post_subscriptions_raw(format!(
+            "name=le%20guin&email=ursula_le_guin%40gmail.com&{}",
+            app.bot_check_fields(60)
+        ))
+        .await;
+
+    // Assert
+    assert_eq!(confirmed_response.status().as_u16(), 200);
+    assert_eq!(new_response.status().as_u16(), 200);
+    // Neither request talked to the email provider: each queued one email
+    assert_eq!(app.email_server.received_requests().await.unwrap().len(), 1);
+    let queued = sqlx::query!(
+        r#"SELECT s.email, q.kind
+        FROM subscription_email_queue q
+        JOIN subscriptions s ON s.id = q.subscriber_id
+        ORDER BY s.email"#
+    )
+    .fetch_all(&app.connection_pool)
+    .await
+    .unwrap();
+    let queued: Vec<_> = queued
+        .iter()
+        .map(|r| (r.email.as_str(), r.kind.as_str()))
+        .collect();
+    assert_eq!(
+        queued,
+        [
+            ("royabhishek77@gmail.com", "already_subscribed"),
+            ("ursula_le_guin@gmail.com", "confirmation"),
+        ]
+    );
+
+    app.dispatch_subscription_emails().await;
+    assert_eq!(app.email_server.received_requests().await.unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn subscribing_a_confirmed_email_sends_nothing_by_default() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=Abhishek%20Roy&email=royabhishek77%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    // The mock asserts on drop that no second email was sent
+}
+
+#[tokio::test]
+async fn an_email_differing_only_in_case_is_the_same_subscriber() {
+    // Arrange
+    let app =
+        spawn_app_with(|settings| settings.application.send_already_subscribed_email = true).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=Abhishek%20Roy&email=royabhishek77%40gmail.com".into())
+        .await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = app
+        .post_subscriptions("name=Abhishek%20Roy&email=RoyAbhishek77%40Gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = &app.email_server.received_requests().await.unwrap()[1];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Subject"], "You're already subscribed");
+    let subscribers = sqlx::query_scalar!("SELECT count(*) FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers, Some(1));
+}
+
+#[tokio::test]
+async fn subscribing_again_after_unsubscribing_sends_a_new_confirmation_link() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=Abhishek%20Roy&email=royabhishek77%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let unsubscribe_links = app.get_unsubscribe_links(email_request);
+    app.post_unsubscribe(unsubscribe_links.html)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = &app.email_server.received_requests().await.unwrap()[1];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.connection_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn subscriber_details_are_redacted_from_the_logs_when_asked_to() {
+    // Arrange
+    let pii_hashing_key = Secret::new(Uuid::new_v4().to_string());
+    let app = spawn_app_with(|settings| {
+        settings.telemetry.redact_pii = true;
+        settings.telemetry.pii_hashing_key = pii_hashing_key.clone();
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let email = format!("{}@example.com", Uuid::new_v4());
+
+    // Act
+    let response = app
+        .post_subscriptions(format!(
+            "name=le%20guin&email={}",
+            email.replace('@', "%40")
+        ))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let redacted_email = PiiRedaction::with_key(&pii_hashing_key)
+        .scope(async { Pii(&email).to_string() })
+        .await;
+    let logs = captured_logs();
+    let subscribe_span = logs
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .find(|line| line["subscriber_email"] == redacted_email.as_str())
+        .expect("The subscribe span was not logged with the redacted email.");
+    assert!(subscribe_span["subscriber_name"]
+        .as_str()
+        .unwrap()
+        .starts_with("redacted:"));
+    assert!(!logs.contains(&email));
+}
@@ -1,5 +1,4 @@
 use crate::helpers::spawn_app;
-use reqwest;
 use wiremock::{
     matchers::{method, path},
     Mock, ResponseTemplate,
@@ -84,3 +83,63 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
     assert_eq!(saved.name, "Abhishek Roy");
     assert_eq!(saved.status, "confirmed");
 }
+
+#[tokio::test]
+async fn expired_confirmation_links_are_rejected() {
+    // Arrange
+    let test_app = spawn_app().await;
+    let body = "name=Abhishek%20Roy&email=royabhishek77%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+
+    test_app.post_subscriptions(body.into()).await;
+    let email_request = &test_app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = test_app.get_confirmation_links(email_request);
+
+    // Pretend the link was sent long before the expiry window
+    sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '1 year'")
+        .execute(&test_app.connection_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = reqwest::get(confirmation_links.html)
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 410);
+    assert!(response.text().await.unwrap().contains("expired"));
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&test_app.connection_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn confirm_fails_if_there_is_a_fatal_database_error() {
+    // Arrange
+    let test_app = spawn_app().await;
+    // Sabotage the database
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN created_at;")
+        .execute(&test_app.connection_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = reqwest::get(&format!(
+        "{}/subscriptions/confirm?subscription_token=abc",
+        test_app.address
+    ))
+    .await
+    .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 500);
+}